@@ -0,0 +1,276 @@
+// Games sent over the network as the difference with a game the
+// receiver already has.  Actors which didn't change are left out, and
+// for the ones which did we only send the fields that changed --
+// except for new actors, and actors that turned into something else,
+// which we send whole.
+
+use geometry::*;
+use input::*;
+
+use {Actor, ActorId, Actors, Bullet, Camera, Game, Scores, Ship, Shooter};
+
+#[inline]
+fn diff<T: PartialEq + Copy>(base: T, new: T) -> Option<T> {
+    if base == new { None } else { Some(new) }
+}
+
+#[inline]
+fn patch<T: Copy>(base: T, delta: Option<T>) -> T {
+    delta.unwrap_or(base)
+}
+
+#[derive(PartialEq, Clone, Copy, Show, RustcEncodable, RustcDecodable)]
+struct ShipDelta {
+    trans: Option<Transform>,
+    vel: Option<Vec2>,
+    not_firing_for: Option<f32>,
+    health: Option<f32>,
+    dead_for: Option<Option<f32>>,
+    killed_by: Option<Option<ActorId>>,
+    accel: Option<bool>,
+    rotating: Option<Rotating>,
+    camera: Option<Camera>,
+}
+
+impl ShipDelta {
+    fn new(base: &Ship, ship: &Ship) -> ShipDelta {
+        ShipDelta{
+            trans: diff(base.trans, ship.trans),
+            vel: diff(base.vel, ship.vel),
+            not_firing_for: diff(base.not_firing_for, ship.not_firing_for),
+            health: diff(base.health, ship.health),
+            dead_for: diff(base.dead_for, ship.dead_for),
+            killed_by: diff(base.killed_by, ship.killed_by),
+            accel: diff(base.accel, ship.accel),
+            rotating: diff(base.rotating, ship.rotating),
+            camera: diff(base.camera, ship.camera),
+        }
+    }
+
+    fn apply(&self, base: &Ship) -> Ship {
+        Ship{
+            spec: base.spec,
+            trans: patch(base.trans, self.trans),
+            vel: patch(base.vel, self.vel),
+            not_firing_for: patch(base.not_firing_for, self.not_firing_for),
+            health: patch(base.health, self.health),
+            dead_for: patch(base.dead_for, self.dead_for),
+            killed_by: patch(base.killed_by, self.killed_by),
+            accel: patch(base.accel, self.accel),
+            rotating: patch(base.rotating, self.rotating),
+            camera: patch(base.camera, self.camera),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Show, RustcEncodable, RustcDecodable)]
+struct ShooterDelta {
+    trans: Option<Transform>,
+    time_since_fire: Option<f32>,
+}
+
+impl ShooterDelta {
+    fn new(base: &Shooter, shooter: &Shooter) -> ShooterDelta {
+        ShooterDelta{
+            trans: diff(base.trans, shooter.trans),
+            time_since_fire: diff(base.time_since_fire, shooter.time_since_fire),
+        }
+    }
+
+    fn apply(&self, base: &Shooter) -> Shooter {
+        Shooter{
+            spec: base.spec,
+            trans: patch(base.trans, self.trans),
+            team: base.team,
+            time_since_fire: patch(base.time_since_fire, self.time_since_fire),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Show, RustcEncodable, RustcDecodable)]
+struct BulletDelta {
+    trans: Option<Transform>,
+    vel: Option<Vec2>,
+    age: Option<f32>,
+}
+
+impl BulletDelta {
+    fn new(base: &Bullet, bullet: &Bullet) -> BulletDelta {
+        BulletDelta{
+            trans: diff(base.trans, bullet.trans),
+            vel: diff(base.vel, bullet.vel),
+            age: diff(base.age, bullet.age),
+        }
+    }
+
+    fn apply(&self, base: &Bullet) -> Bullet {
+        Bullet{
+            spec: base.spec,
+            owner: base.owner,
+            trans: patch(base.trans, self.trans),
+            vel: patch(base.vel, self.vel),
+            age: patch(base.age, self.age),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Show, RustcEncodable, RustcDecodable)]
+enum ActorDelta {
+    Whole(Actor),
+    Ship(ShipDelta),
+    Shooter(ShooterDelta),
+    Bullet(BulletDelta),
+}
+
+impl ActorDelta {
+    // `None` if the actor didn't change.
+    fn new(base: Option<&Actor>, actor: &Actor) -> Option<ActorDelta> {
+        match (base, actor) {
+            (Some(base), actor) if base == actor =>
+                None,
+            (Some(&Actor::Ship(ref base)), &Actor::Ship(ref ship)) if base.spec == ship.spec =>
+                Some(ActorDelta::Ship(ShipDelta::new(base, ship))),
+            (Some(&Actor::Shooter(ref base)), &Actor::Shooter(ref shooter)) if base.spec == shooter.spec && base.team == shooter.team =>
+                Some(ActorDelta::Shooter(ShooterDelta::new(base, shooter))),
+            (Some(&Actor::Bullet(ref base)), &Actor::Bullet(ref bullet)) if base.spec == bullet.spec && base.owner == bullet.owner =>
+                Some(ActorDelta::Bullet(BulletDelta::new(base, bullet))),
+            (_, actor) =>
+                Some(ActorDelta::Whole(*actor)),
+        }
+    }
+
+    // `None` if the delta is not for the base we've got.
+    fn apply(&self, base: Option<&Actor>) -> Option<Actor> {
+        match (self, base) {
+            (&ActorDelta::Whole(actor), _) =>
+                Some(actor),
+            (&ActorDelta::Ship(ref delta), Some(&Actor::Ship(ref base))) =>
+                Some(Actor::Ship(delta.apply(base))),
+            (&ActorDelta::Shooter(ref delta), Some(&Actor::Shooter(ref base))) =>
+                Some(Actor::Shooter(delta.apply(base))),
+            (&ActorDelta::Bullet(ref delta), Some(&Actor::Bullet(ref base))) =>
+                Some(Actor::Bullet(delta.apply(base))),
+            _ =>
+                None,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Show, RustcEncodable, RustcDecodable)]
+pub struct GameDelta {
+    removed: Vec<ActorId>,
+    changed: Vec<(ActorId, ActorDelta)>,
+    count: ActorId,
+    // Scores change rarely, and they're small
+    scores: Option<Scores>,
+    tick: u32,
+    time: f32,
+}
+
+impl GameDelta {
+    pub fn new(base: &Game, game: &Game) -> GameDelta {
+        let removed = base.actors.keys()
+            .filter(|actor_id| game.actors.get(**actor_id).is_none())
+            .map(|actor_id| *actor_id)
+            .collect();
+        let mut changed = Vec::new();
+        for (actor_id, actor) in game.actors.iter() {
+            match ActorDelta::new(base.actors.get(*actor_id), actor) {
+                None        => {},
+                Some(delta) => changed.push((*actor_id, delta)),
+            }
+        }
+        GameDelta{
+            removed: removed,
+            changed: changed,
+            count: game.actors.count,
+            scores: if base.scores == game.scores { None } else { Some(game.scores.clone()) },
+            tick: game.tick,
+            time: game.time,
+        }
+    }
+
+    // `None` if the delta was not taken against `base`.
+    pub fn apply(&self, base: &Game) -> Option<Game> {
+        let mut actors = Actors{
+            actors: base.actors.actors.clone(),
+            count: self.count,
+        };
+        for actor_id in self.removed.iter() {
+            let _ = actors.remove(*actor_id);
+        }
+        for &(actor_id, ref delta) in self.changed.iter() {
+            let actor = delta.apply(actors.get(actor_id));
+            match actor {
+                None        => return None,
+                Some(actor) => actors.insert(actor_id, actor),
+            }
+        }
+        Some(Game{
+            actors: actors,
+            scores: match self.scores {
+                None             => base.scores.clone(),
+                Some(ref scores) => scores.clone(),
+            },
+            tick: self.tick,
+            time: self.time,
+        })
+    }
+}
+
+// ---------------------------------------------------------------------
+// Tests
+
+#[test]
+fn test_delta() {
+    let ship = Ship{
+        spec: 0,
+        trans: Transform{pos: Vec2{x: 100., y: 100.}, rotation: 0.},
+        vel: Vec2{x: 10., y: 0.},
+        not_firing_for: 0.,
+        health: 100.,
+        dead_for: None,
+        killed_by: None,
+        accel: false,
+        rotating: Rotating::Still,
+        camera: Camera{pos: Vec2{x: 0., y: 0.}, vel: Vec2{x: 0., y: 0.}},
+    };
+    let bullet = Bullet{
+        spec: 1,
+        owner: 0,
+        trans: Transform{pos: Vec2{x: 0., y: 0.}, rotation: 0.},
+        vel: Vec2{x: 100., y: 0.},
+        age: 0.,
+    };
+    let mut base = Game::empty();
+    let ship_id = base.actors.add(Actor::Ship(ship));
+    base.scores.add_player(ship_id);
+    let gone_id = base.actors.add(Actor::Bullet(bullet));
+    let moving_id = base.actors.add(Actor::Bullet(bullet));
+    for _ in range(0, 100us) {
+        let _ = base.actors.add(Actor::Bullet(bullet));
+    }
+
+    let mut game = base.clone();
+    game.tick = 1;
+    game.time = 0.05;
+    let _ = game.actors.remove(gone_id);
+    game.actors.insert(moving_id, Actor::Bullet(Bullet{age: 0.05, ..bullet}));
+    game.actors.insert(ship_id, Actor::Ship(Ship{health: 75., ..ship}));
+    let _ = game.actors.add(Actor::Bullet(bullet));
+
+    let delta = GameDelta::new(&base, &game);
+    assert!(delta.removed == vec![gone_id]);
+    assert!(delta.changed.len() == 3);
+    assert!(delta.scores.is_none());
+    assert!(delta.apply(&base) == Some(game.clone()));
+
+    // Against the empty game we get the whole thing
+    let full = GameDelta::new(&Game::empty(), &game);
+    assert!(full.changed.len() == game.actors.len());
+    assert!(full.apply(&Game::empty()) == Some(game.clone()));
+
+    // We can tell if the base is wrong
+    let _ = base.actors.remove(ship_id);
+    assert!(delta.apply(&base).is_none());
+}
@@ -0,0 +1,182 @@
+// Broadphase for the interactions: every tick the actors are bucketed
+// in a uniform grid based on their bounding rectangle, and only the
+// ones close enough to touch are handed to the expensive SAT test in
+// `BBox::overlapping`.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::num::Float;
+
+use geometry::*;
+
+use ActorId;
+
+#[derive(Clone, Copy)]
+struct Item {
+    id: ActorId,
+    bounds: Rect,
+    active: bool,
+}
+
+pub struct Grid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<Item>>,
+}
+
+// The top-left corner of the intersection of the two rects, if they
+// intersect.
+#[inline]
+fn intersection_corner(a: &Rect, b: &Rect) -> Option<Vec2> {
+    let separated =
+        a.pos.x + a.w < b.pos.x || b.pos.x + b.w < a.pos.x ||
+        a.pos.y + a.h < b.pos.y || b.pos.y + b.h < a.pos.y;
+    if separated {
+        None
+    } else {
+        Some(Vec2{x: a.pos.x.max(b.pos.x), y: a.pos.y.max(b.pos.y)})
+    }
+}
+
+impl Grid {
+    pub fn new(cell_size: f32) -> Grid {
+        Grid{cell_size: cell_size, cells: HashMap::new()}
+    }
+
+    #[inline]
+    fn cell(&self, p: Vec2) -> (i32, i32) {
+        ((p.x / self.cell_size).floor() as i32, (p.y / self.cell_size).floor() as i32)
+    }
+
+    // Active actors can touch anything, passive ones only active ones:
+    // bullets don't hit each other, so we don't want to pay for
+    // bullet/bullet pairs.
+    pub fn insert(&mut self, id: ActorId, bounds: Rect, active: bool) {
+        let item = Item{id: id, bounds: bounds, active: active};
+        let (min_x, min_y) = self.cell(bounds.pos);
+        let (max_x, max_y) = self.cell(bounds.pos + Vec2{x: bounds.w, y: bounds.h});
+        for x in range(min_x, max_x + 1) {
+            for y in range(min_y, max_y + 1) {
+                match self.cells.entry((x, y)) {
+                    Entry::Vacant(entry)       => { let _ = entry.insert(vec![item]); },
+                    Entry::Occupied(mut entry) => entry.get_mut().push(item),
+                }
+            }
+        }
+    }
+
+    // All the pairs with overlapping bounds and at least one active
+    // actor, each reported once.
+    pub fn pairs(&self) -> Vec<(ActorId, ActorId)> {
+        let mut pairs = Vec::new();
+        for (cell, items) in self.cells.iter() {
+            for i in range(0, items.len()) {
+                let this = &items[i];
+                if !this.active { continue };
+                for j in range(0, items.len()) {
+                    let other = &items[j];
+                    // Active/active pairs would be found twice
+                    if j == i || (other.active && j < i) { continue };
+                    match intersection_corner(&this.bounds, &other.bounds) {
+                        // Actors spanning more than one cell would also
+                        // be found more than once, so we only report
+                        // them in the cell where their intersection
+                        // starts.
+                        Some(corner) if self.cell(corner) == *cell =>
+                            pairs.push((this.id, other.id)),
+                        _ =>
+                            {},
+                    }
+                }
+            }
+        }
+        pairs
+    }
+
+    // For each actor, the actors it might interact with.  Actors which
+    // are not near anything are missing.
+    pub fn neighbours(&self) -> HashMap<ActorId, Vec<ActorId>> {
+        let mut neighbours: HashMap<ActorId, Vec<ActorId>> = HashMap::new();
+        for &(this, other) in self.pairs().iter() {
+            for &(from, to) in [(this, other), (other, this)].iter() {
+                match neighbours.entry(from) {
+                    Entry::Vacant(entry)       => { let _ = entry.insert(vec![to]); },
+                    Entry::Occupied(mut entry) => entry.get_mut().push(to),
+                }
+            }
+        }
+        neighbours
+    }
+}
+
+// ---------------------------------------------------------------------
+// Tests
+
+// A few ships and lots of bullets scattered over a 10x10 screens map.
+// The scattering is cheap and deterministic.
+#[cfg(test)]
+fn scattered(ships: usize, bullets: usize) -> Vec<Item> {
+    range(0, ships + bullets).map(|i| {
+        let pos = Vec2{x: ((i * 7919) % 8000) as f32, y: ((i * 104729) % 6000) as f32};
+        let (size, active) = if i < ships { (40., true) } else { (12., false) };
+        Item{
+            id: i as ActorId,
+            bounds: Rect{pos: pos, w: size, h: size},
+            active: active,
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+fn grid_pairs(items: &Vec<Item>) -> Vec<(ActorId, ActorId)> {
+    let mut grid = Grid::new(::conf::COLLISION_CELL_SIZE);
+    for item in items.iter() {
+        grid.insert(item.id, item.bounds, item.active);
+    }
+    grid.pairs()
+}
+
+#[cfg(test)]
+fn brute_force_pairs(items: &Vec<Item>) -> Vec<(ActorId, ActorId)> {
+    let mut pairs = Vec::new();
+    for i in range(0, items.len()) {
+        for j in range(i + 1, items.len()) {
+            let (this, other) = (&items[i], &items[j]);
+            if (this.active || other.active) && intersection_corner(&this.bounds, &other.bounds).is_some() {
+                pairs.push((this.id, other.id));
+            }
+        }
+    }
+    pairs
+}
+
+#[test]
+fn test_pairs() {
+    // Lots of ships, so that they overlap with each other too.
+    let items = scattered(500, 2000);
+    let mut pairs: Vec<(ActorId, ActorId)> = grid_pairs(&items).iter().map(|&(this, other)| {
+        if this < other { (this, other) } else { (other, this) }
+    }).collect();
+    pairs.sort();
+    let mut expected = brute_force_pairs(&items);
+    expected.sort();
+    assert!(!expected.is_empty());
+    assert!(pairs == expected);
+}
+
+#[bench]
+fn bench_grid_1000_bullets(b: &mut ::test::Bencher) {
+    let items = scattered(20, 1000);
+    b.iter(|| grid_pairs(&items));
+}
+
+#[bench]
+fn bench_grid_5000_bullets(b: &mut ::test::Bencher) {
+    let items = scattered(20, 5000);
+    b.iter(|| grid_pairs(&items));
+}
+
+#[bench]
+fn bench_brute_force_1000_bullets(b: &mut ::test::Bencher) {
+    let items = scattered(20, 1000);
+    b.iter(|| brute_force_pairs(&items));
+}
@@ -0,0 +1,156 @@
+// Lag compensation: bullets fired by players on a slow connection are
+// checked against the ships as those players saw them, rather than as
+// they are now, so that they hit what was aimed at.  The hits are
+// worked out before the interactions, so that the bullets and the ships
+// agree on them.
+
+use std::cmp::min;
+use std::collections::{HashMap, RingBuf};
+use std::collections::hash_map::Entry;
+
+use specs::*;
+use conf::*;
+
+use {bullet_hits_ship, Actor, ActorId, Actors, Game, Grid};
+#[cfg(test)] use test_spec;
+
+/// How far behind the server each player is seeing the game.
+pub struct Lag<'a> {
+    /// The latest games, most recent first, starting with the one being
+    /// advanced: `history[n]` is the game `n` ticks back.
+    pub history: &'a RingBuf<Game>,
+    /// In ticks.  Players who are missing aren't behind at all.
+    pub ticks: &'a HashMap<ActorId, usize>,
+}
+
+/// The bullets checked against past ships, and the ships they hit.
+pub struct LagHits {
+    hits: HashMap<ActorId, Vec<ActorId>>,
+}
+
+impl LagHits {
+    pub fn new(sspec: &GameSpec, actors: &Actors, lag: &Lag, dt: f32) -> LagHits {
+        let mut hits = HashMap::new();
+        if lag.history.is_empty() { return LagHits{hits: hits} };
+
+        // Bullets whose owners are equally far behind are checked
+        // against the same game
+        let mut bullets: HashMap<usize, Vec<ActorId>> = HashMap::new();
+        for (actor_id, actor) in actors.iter() {
+            match *actor {
+                Actor::Bullet(ref bullet) => match lag.ticks.get(&bullet.owner) {
+                    Some(&ticks) if ticks > 0 => match bullets.entry(ticks) {
+                        Entry::Vacant(entry)       => { let _ = entry.insert(vec![*actor_id]); },
+                        Entry::Occupied(mut entry) => entry.get_mut().push(*actor_id),
+                    },
+                    _ => {},
+                },
+                _ => {},
+            }
+        };
+
+        for (ticks, bullet_ids) in bullets.iter() {
+            let past = lag.history.get(min(*ticks, lag.history.len() - 1)).unwrap();
+            let mut grid = Grid::new(COLLISION_CELL_SIZE);
+            for (ship_id, ship) in past.actors.iter() {
+                match *ship {
+                    Actor::Ship(_) => grid.insert(*ship_id, ship.bounds(sspec, dt), true),
+                    _              => {},
+                }
+            };
+            for bullet_id in bullet_ids.iter() {
+                let _ = hits.insert(*bullet_id, Vec::new());
+                grid.insert(*bullet_id, actors.get(*bullet_id).unwrap().bounds(sspec, dt), false);
+            };
+            for &(ship_id, bullet_id) in grid.pairs().iter() {
+                // Ships touching each other come up too
+                if !hits.contains_key(&bullet_id) { continue };
+                let bullet = match *actors.get(bullet_id).unwrap() {
+                    Actor::Bullet(ref bullet) => bullet,
+                    _                         => unreachable!(),
+                };
+                let past_ship = past.actors.get(ship_id).unwrap().is_ship();
+                // Ships which died or left since can't be hit anymore
+                let alive = match actors.get(ship_id) {
+                    Some(&Actor::Ship(ref ship)) => ship.alive(),
+                    _                            => false,
+                };
+                if alive && bullet_hits_ship(sspec, bullet, ship_id, past_ship, dt) {
+                    hits.get_mut(&bullet_id).unwrap().push(ship_id);
+                }
+            };
+        };
+        LagHits{hits: hits}
+    }
+
+    /// Whether the bullet is checked against past ships, rather than
+    /// the ones it's near to now.
+    #[inline]
+    pub fn compensated(&self, bullet_id: ActorId) -> bool {
+        self.hits.contains_key(&bullet_id)
+    }
+
+    #[inline]
+    pub fn bullet_hit(&self, bullet_id: ActorId) -> bool {
+        match self.hits.get(&bullet_id) {
+            None       => false,
+            Some(hits) => !hits.is_empty(),
+        }
+    }
+
+    /// The compensated bullets which hit the ship.
+    pub fn ship_hit_by(&self, ship_id: ActorId) -> Vec<ActorId> {
+        self.hits.iter()
+            .filter(|&(_, ships)| ships.contains(&ship_id))
+            .map(|(bullet_id, _)| *bullet_id)
+            .collect()
+    }
+}
+
+// ---------------------------------------------------------------------
+// Tests
+
+#[test]
+fn test_lag_hits() {
+    use geometry::*;
+    use {Bullet, Ship};
+
+    let spec = test_spec();
+    let mut before = Game::empty();
+    let target = before.add_ship(&spec);
+    let target_ship = *before.actors.get(target).unwrap().is_ship();
+
+    // The target has since flown away, but the shooter saw it where it was
+    let mut game = before.clone();
+    game.actors.insert(target, Actor::Ship(Ship{
+        trans: Transform::pos(Vec2{x: 2000., y: 2000.}),
+        ..target_ship
+    }));
+    let shooter = target + 1000;
+    let bullet = game.actors.add(Actor::Bullet(Bullet{
+        spec: spec.get_spec(spec.ship_spec).is_ship().bullet_spec,
+        owner: shooter,
+        trans: target_ship.trans,
+        vel: Vec2::zero(),
+        age: TIME_STEP,
+    }));
+
+    // Without lag compensation it's a miss
+    let missed = game.advance(&spec, &Vec::new(), TIME_STEP);
+    assert!(missed.actors.get(bullet).is_some());
+    assert!(missed.tick == game.tick + 1);
+
+    let mut history = RingBuf::new();
+    history.push_back(game.clone());
+    history.push_back(before.clone());
+    let mut ticks = HashMap::new();
+    let _ = ticks.insert(shooter, 1);
+    let hit = game.advance_lagged(&spec, &Vec::new(), &Lag{history: &history, ticks: &ticks}, TIME_STEP);
+    assert!(hit.actors.get(bullet).is_none());
+    assert!(hit.actors.get(target).unwrap().is_ship().health < target_ship.health);
+
+    // Further back than we have is as far back as we have
+    let _ = ticks.insert(shooter, 10);
+    let hit = game.advance_lagged(&spec, &Vec::new(), &Lag{history: &history, ticks: &ticks}, TIME_STEP);
+    assert!(hit.actors.get(bullet).is_none());
+}
@@ -6,8 +6,9 @@ extern crate input;
 extern crate specs;
 extern crate conf;
 extern crate physics;
+#[cfg(test)] extern crate test;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, RingBuf};
 use std::collections::hash_map::{Keys, Values, Iter};
 use std::num::Float;
 use std::sync::Arc;
@@ -18,6 +19,14 @@ use input::*;
 use specs::*;
 use conf::*;
 
+pub use grid::Grid;
+pub use delta::GameDelta;
+pub use lag::{Lag, LagHits};
+
+mod grid;
+mod delta;
+mod lag;
+
 #[derive(PartialEq, Clone, Show, Copy, RustcEncodable, RustcDecodable)]
 pub struct Camera {
     pub pos: Vec2,
@@ -43,7 +52,7 @@ impl Camera {
     pub fn advance(self, sspec: &GameSpec, ship_vel: Vec2, ship_trans: Transform, dt: f32) -> Camera {
         let mut cam = self;
         let spec = sspec.camera_spec;
-        let map = sspec.map;
+        let map = &sspec.map;
 
         // Push the camera based on the ship vel
         cam.vel = ship_vel * spec.accel;
@@ -71,20 +80,41 @@ impl Camera {
 #[derive(PartialEq, Clone, Copy, Show, RustcDecodable, RustcEncodable)]
 pub struct Bullet {
     pub spec: SpecId,
+    // The actor who fired the bullet
+    pub owner: ActorId,
     pub trans: Transform,
+    pub vel: Vec2,
     pub age: f32,
 }
 
 impl Bullet {
+    // Fires a bullet from `trans`.  `vel` is the velocity of whoever is
+    // firing, which the bullet keeps on top of its own.
+    pub fn fire(sspec: &GameSpec, spec_id: SpecId, owner: ActorId, trans: Transform, vel: Vec2) -> Bullet {
+        let spec = sspec.get_spec(spec_id).is_bullet();
+        Bullet{
+            spec: spec_id,
+            owner: owner,
+            trans: trans,
+            vel: vel + Vec2{x: spec.vel, y: 0.}.rotate(trans.rotation),
+            age: 0.,
+        }
+    }
+
     pub fn advance(&self, sspec: &GameSpec, _: &mut Actors, dt: f32) -> Option<Bullet> {
         let spec = sspec.get_spec(self.spec).is_bullet();
-        let pos = Vec2 {
-            x: self.trans.pos.x + (spec.vel * self.trans.rotation.cos() * dt),
-            y: self.trans.pos.y + (-1. * spec.vel * self.trans.rotation.sin() * dt),
+        let vel = self.vel + Vec2{x: 0., y: spec.gravity * dt};
+        // Point the bullet where it's going
+        let rotation = if spec.gravity == 0. {
+            self.trans.rotation
+        } else {
+            (-vel.y).atan2(vel.x)
         };
         let bullet = Bullet {
             spec: self.spec,
-            trans: Transform{pos: pos, rotation: self.trans.rotation},
+            owner: self.owner,
+            trans: Transform{pos: self.trans.pos + vel * dt, rotation: rotation},
+            vel: vel,
             age: self.age + dt,
         };
         let alive =
@@ -93,6 +123,64 @@ impl Bullet {
             bullet.age < spec.lifetime;
         if alive { Some(bullet) } else { None }
     }
+
+    // Where the bullet went over the last `dt`, it wasn't going anywhere
+    // before being fired.
+    #[inline]
+    fn travel(&self, dt: f32) -> Vec2 {
+        self.vel * dt.min(self.age)
+    }
+
+    pub fn interact(&self, sspec: &GameSpec, actors: &Actors, id: ActorId, nearby: &[ActorId], lag_hits: &LagHits, dt: f32) -> Option<Bullet> {
+        if lag_hits.compensated(id) {
+            return if lag_hits.bullet_hit(id) { None } else { Some(*self) };
+        }
+        for other_id in nearby.iter() {
+            match *actors.get(*other_id).unwrap() {
+                Actor::Ship(ref ship) =>
+                    if bullet_hits_ship(sspec, self, *other_id, ship, dt) { return None },
+                _ => {},
+            }
+        };
+        Some(*self)
+    }
+}
+
+// Both the bullet and the ship use this to decide whether they've
+// collided, so that they always agree.  Bullets easily go further than
+// a ship is wide in a tick, so we check the whole way they went over
+// the last `dt`, in steps too short to jump over the ship.
+#[inline]
+fn bullet_hits_ship(sspec: &GameSpec, bullet: &Bullet, ship_id: ActorId, ship: &Ship, dt: f32) -> bool {
+    // Bullets are fired from inside the ship bbox, so we only make them
+    // harmful after the first tick, and don't look at where they came
+    // from on their way out -- otherwise ships would shoot themselves.
+    if bullet.age == 0. || !ship.alive() { return false };
+    let bullet_spec = sspec.get_spec(bullet.spec).is_bullet();
+    let ship_spec = sspec.get_spec(ship.spec).is_ship();
+    let travel = if bullet.owner == ship_id && bullet.age <= dt { Vec2::zero() } else { bullet.travel(dt) };
+    let step = bullet_spec.bbox.min_side() + ship_spec.bbox.min_side();
+    let steps = (travel.mag() / step).ceil().max(1.) as usize;
+    range(0, steps + 1).any(|i| {
+        let trans = Transform{pos: bullet.trans.pos - travel * (i as f32 / steps as f32), ..bullet.trans};
+        BBox::overlapping(&bullet_spec.bbox, &trans, &ship_spec.bbox, &ship.trans)
+    })
+}
+
+#[inline]
+fn ships_collide(sspec: &GameSpec, ship: &Ship, other: &Ship) -> bool {
+    if !ship.alive() || !other.alive() { return false };
+    let ship_spec = sspec.get_spec(ship.spec).is_ship();
+    let other_spec = sspec.get_spec(other.spec).is_ship();
+    BBox::overlapping(&ship_spec.bbox, &ship.trans, &other_spec.bbox, &other.trans)
+}
+
+#[inline]
+fn ship_hits_shooter(sspec: &GameSpec, ship: &Ship, shooter: &Shooter) -> bool {
+    if !ship.alive() { return false };
+    let ship_spec = sspec.get_spec(ship.spec).is_ship();
+    let shooter_spec = sspec.get_spec(shooter.spec).is_shooter();
+    BBox::overlapping(&ship_spec.bbox, &ship.trans, &shooter_spec.bbox, &shooter.trans)
 }
 
 
@@ -102,6 +190,11 @@ pub struct Ship {
     pub trans: Transform,
     pub vel: Vec2,
     pub not_firing_for: f32,
+    pub health: f32,
+    // `Some(t)` if the ship has been dead for `t` seconds
+    pub dead_for: Option<f32>,
+    // Who dealt the final blow, if the ship is dead
+    pub killed_by: Option<ActorId>,
     pub accel: bool,
     pub rotating: Rotating,
     pub camera: Camera,
@@ -137,12 +230,16 @@ impl<'a> ::physics::Acceleration for ShipState<'a> {
 }
 
 impl Ship {
-    pub fn new(spec_id: SpecId, pos: Vec2) -> Ship {
+    pub fn new(sspec: &GameSpec, spec_id: SpecId, pos: Vec2) -> Ship {
+        let spec = sspec.get_spec(spec_id).is_ship();
         Ship{
             spec: spec_id,
             trans: Transform::pos(pos),
             vel: Vec2::zero(),
             not_firing_for: 100000.,
+            health: spec.health,
+            dead_for: None,
+            killed_by: None,
             accel: false,
             rotating: Rotating::Still,
             camera: Camera{
@@ -155,8 +252,31 @@ impl Ship {
         }
     }
 
-    pub fn advance(&self, sspec: &GameSpec, actors: &mut Actors, input: Option<Input>, dt: f32) -> Option<Ship> {
+    #[inline]
+    pub fn alive(&self) -> bool {
+        self.dead_for.is_none()
+    }
+
+    pub fn advance(&self, sspec: &GameSpec, actors: &mut Actors, id: ActorId, input: Option<Input>, dt: f32) -> Option<Ship> {
         let spec = sspec.get_spec(self.spec).is_ship();
+
+        // =============================================================
+        // Dead ships just wait to respawn, keeping their actor id
+        match self.dead_for {
+            None => {},
+            Some(dead_for) => {
+                let dead_for = dead_for + dt;
+                if dead_for >= spec.respawn_delay {
+                    let pos = sspec.furthest_spawn_point(self.trans.pos);
+                    return Some(Ship::new(sspec, self.spec, pos));
+                } else {
+                    let mut ship = *self;
+                    ship.dead_for = Some(dead_for);
+                    return Some(ship);
+                }
+            },
+        }
+
         let mut not_firing_for = self.not_firing_for + dt;
         let (accel, rotating, firing) =
             match input {
@@ -184,8 +304,8 @@ impl Ship {
         let rotation_delta = dt * rotation_vel;
         match rotating {
             Rotating::Still => {},
-            Rotating::Left  => trans.rotation += rotation_delta,
-            Rotating::Right => trans.rotation -= rotation_delta,
+            Rotating::Left  => trans.rotation = normalize_angle(trans.rotation + rotation_delta),
+            Rotating::Right => trans.rotation = normalize_angle(trans.rotation - rotation_delta),
         }
 
         // =============================================================
@@ -204,11 +324,7 @@ impl Ship {
         // Add new bullet
         if firing {
             let shoot_from = spec.shoot_from.rotate(trans.rotation);
-            let bullet = Bullet {
-                spec: spec.bullet_spec,
-                trans: trans + shoot_from,
-                age: 0.,
-            };
+            let bullet = Bullet::fire(sspec, spec.bullet_spec, id, trans + shoot_from, vel);
             let _ = actors.add(Actor::Bullet(bullet));
         }
         
@@ -217,34 +333,180 @@ impl Ship {
             trans: trans,
             vel: vel,
             not_firing_for: not_firing_for,
+            health: self.health,
+            dead_for: None,
+            killed_by: None,
             accel: accel,
             rotating: rotating,
             camera: camera,
         };
         Some(new)
     }
+
+    // Bounces the ship off something at `pos` moving at `vel`.
+    // `weight` is how much of the impulse the ship takes: 0.5 when
+    // hitting another ship, 1 when hitting something that doesn't
+    // move.  Returns `false`, leaving the ship alone, if the two are
+    // already moving apart.
+    fn bounce(&mut self, spec: &ShipSpec, pos: Vec2, vel: Vec2, weight: f32) -> bool {
+        let dist = self.trans.pos - pos;
+        if dist.mag() == 0. { return false };
+        let normal = dist.norm();
+        let approaching = (self.vel - vel).dot(normal);
+        if approaching >= 0. { return false };
+        self.vel = self.vel - normal * (approaching * (1. + spec.restitution) * weight);
+        true
+    }
+
+    fn hit_by(&mut self, sspec: &GameSpec, bullet: &Bullet) {
+        self.health -= sspec.get_spec(bullet.spec).is_bullet().damage;
+        self.killed_by = Some(bullet.owner);
+    }
+
+    pub fn interact(&self, sspec: &GameSpec, actors: &Actors, id: ActorId, nearby: &[ActorId], lag_hits: &LagHits, dt: f32) -> Option<Ship> {
+        let spec = sspec.get_spec(self.spec).is_ship();
+        let mut ship = *self;
+        for other_id in nearby.iter() {
+            match *actors.get(*other_id).unwrap() {
+                Actor::Bullet(ref bullet) =>
+                    if !lag_hits.compensated(*other_id) && bullet_hits_ship(sspec, bullet, id, self, dt) {
+                        ship.hit_by(sspec, bullet);
+                    },
+                Actor::Ship(ref other) =>
+                    // Crashing into somebody else is our own fault
+                    if *other_id != id && ships_collide(sspec, self, other) {
+                        if ship.bounce(spec, other.trans.pos, other.vel, 0.5) {
+                            ship.health -= spec.collision_damage;
+                            ship.killed_by = Some(id);
+                        }
+                    },
+                Actor::Shooter(ref shooter) =>
+                    if ship_hits_shooter(sspec, self, shooter) {
+                        if ship.bounce(spec, shooter.trans.pos, Vec2::zero(), 1.) {
+                            ship.health -= spec.collision_damage;
+                            ship.killed_by = Some(*other_id);
+                        }
+                    },
+            }
+        };
+        for bullet_id in lag_hits.ship_hit_by(id).iter() {
+            match *actors.get(*bullet_id).unwrap() {
+                Actor::Bullet(ref bullet) => ship.hit_by(sspec, bullet),
+                _                         => unreachable!(),
+            }
+        };
+        if ship.alive() && ship.health <= 0. {
+            ship.dead_for = Some(0.);
+            ship.vel = Vec2::zero();
+            ship.accel = false;
+            ship.rotating = Rotating::Still;
+        }
+        if ship.alive() { ship.killed_by = None };
+        Some(ship)
+    }
 }
 
 #[derive(PartialEq, Clone, Copy, Show, RustcEncodable, RustcDecodable)]
 pub struct Shooter {
     pub spec: SpecId,
+    pub trans: Transform,
+    pub team: Option<TeamId>,
     pub time_since_fire: f32,
 }
 
+// The rotation a shooter at `pos` should fire at to hit a target at
+// `target_pos` moving at `target_vel`, with bullets going at
+// `bullet_vel`.  If the bullets can't catch up we just aim at the
+// target.  Both are taken to keep going in a straight line, so the lead
+// is only right for bullets with no `gravity`, and misses a bit as the
+// target turns or accelerates.
+fn lead_rotation(pos: Vec2, target_pos: Vec2, target_vel: Vec2, bullet_vel: f32) -> f32 {
+    // Find the smallest positive `t` such that
+    // `|target_pos - pos + target_vel*t| = bullet_vel*t`
+    let p = target_pos - pos;
+    let a = target_vel.dot(target_vel) - bullet_vel*bullet_vel;
+    let b = 2. * p.dot(target_vel);
+    let c = p.dot(p);
+    let t = if a == 0. {
+        if b < 0. { -c / b } else { -1. }
+    } else {
+        let discriminant = b*b - 4.*a*c;
+        if discriminant < 0. {
+            -1.
+        } else {
+            let t1 = (-b - discriminant.sqrt()) / (2.*a);
+            let t2 = (-b + discriminant.sqrt()) / (2.*a);
+            if t1 > 0. && (t1 < t2 || t2 <= 0.) { t1 } else { t2 }
+        }
+    };
+    let aim = if t > 0. { p + target_vel * t } else { p };
+    // The y axis goes down, see `Vec2::rotate`
+    (-aim.y).atan2(aim.x)
+}
+
 impl Shooter {
-    pub fn advance(&self, sspec: &GameSpec, actors: &mut Actors, dt: f32) -> Option<Shooter> {
+    pub fn new(placement: &Placement) -> Shooter {
+        Shooter{
+            spec: placement.spec,
+            trans: placement.trans,
+            team: placement.team,
+            time_since_fire: 0.,
+        }
+    }
+
+    // The closest living ship within the detection radius
+    fn target<'a>(&self, spec: &ShooterSpec, world: &'a Actors) -> Option<&'a Ship> {
+        let mut target: Option<&Ship> = None;
+        let mut target_dist = spec.detection_radius;
+        for actor in world.values() {
+            match *actor {
+                Actor::Ship(ref ship) if ship.alive() => {
+                    let dist = (ship.trans.pos - self.trans.pos).mag();
+                    if dist <= target_dist {
+                        target = Some(ship);
+                        target_dist = dist;
+                    }
+                },
+                _ => {},
+            }
+        };
+        target
+    }
+
+    pub fn advance(&self, sspec: &GameSpec, world: &Actors, actors: &mut Actors, id: ActorId, dt: f32) -> Option<Shooter> {
         let spec = sspec.get_spec(self.spec).is_shooter();
+        let mut trans = self.trans;
+        let target = match spec.aim {
+            Aim::Fixed    => None,
+            Aim::Tracking => self.target(spec, world),
+        };
+        match target {
+            None => {},
+            Some(ship) => {
+                let bullet_spec = sspec.get_spec(spec.bullet_spec).is_bullet();
+                let wanted = lead_rotation(trans.pos, ship.trans.pos, ship.vel, bullet_spec.vel);
+                let max_delta = spec.rotation_vel * dt;
+                let delta = normalize_angle(wanted - trans.rotation);
+                let delta = if delta > max_delta {
+                    max_delta
+                } else if delta < -max_delta {
+                    -max_delta
+                } else {
+                    delta
+                };
+                trans.rotation = normalize_angle(trans.rotation + delta);
+            },
+        }
         let mut time_since_fire = self.time_since_fire + dt;
         if time_since_fire > spec.firing_rate {
             time_since_fire = 0.;
-            let bullet = Bullet {
-                spec: spec.bullet_spec,
-                trans: spec.trans,
-                age: 0.,
-            };
+            let bullet = Bullet::fire(sspec, spec.bullet_spec, id, trans, Vec2::zero());
             let _ = actors.add(Actor::Bullet(bullet));
         }
-        Some(Shooter{spec: self.spec, time_since_fire: time_since_fire})
+        let mut shooter = *self;
+        shooter.trans = trans;
+        shooter.time_since_fire = time_since_fire;
+        Some(shooter)
     }
 }
 
@@ -257,14 +519,15 @@ pub enum Actor {
 }
 
 impl Actor {
-    // Returns whether the actor is still alive
-    pub fn advance(&self, sspec: &GameSpec, actors: &mut Actors, input: Option<Input>, dt: f32) -> Option<Actor> {
+    // Returns whether the actor is still alive.  `world` is the state
+    // we're advancing from, new actors are added to `actors`.
+    pub fn advance(&self, sspec: &GameSpec, world: &Actors, actors: &mut Actors, id: ActorId, input: Option<Input>, dt: f32) -> Option<Actor> {
         match *self {
             Actor::Ship(ref ship) =>
-                ship.advance(sspec, actors, input, dt).map(|x| Actor::Ship(x)),
+                ship.advance(sspec, actors, id, input, dt).map(|x| Actor::Ship(x)),
             Actor::Shooter(ref shooter) => {
                 assert!(input.is_none());
-                shooter.advance(sspec, actors, dt).map(|x| Actor::Shooter(x))
+                shooter.advance(sspec, world, actors, id, dt).map(|x| Actor::Shooter(x))
             },
             Actor::Bullet(ref bullet) => {
                 assert!(input.is_none());
@@ -273,8 +536,42 @@ impl Actor {
         }
     }
 
-    pub fn interact(&self, _: &GameSpec, _: &Actors) -> Option<Actor> {
-        Some(*self)
+    // Returns whether the actor survived the interactions.  `nearby`
+    // are the actors it might be touching, see `Grid`, and `lag_hits`
+    // the hits worked out beforehand, see `LagHits`.
+    pub fn interact(&self, sspec: &GameSpec, actors: &Actors, id: ActorId, nearby: &[ActorId], lag_hits: &LagHits, dt: f32) -> Option<Actor> {
+        match *self {
+            Actor::Ship(ref ship) =>
+                ship.interact(sspec, actors, id, nearby, lag_hits, dt).map(|x| Actor::Ship(x)),
+            Actor::Shooter(_) =>
+                Some(*self),
+            Actor::Bullet(ref bullet) =>
+                bullet.interact(sspec, actors, id, nearby, lag_hits, dt).map(|x| Actor::Bullet(x)),
+        }
+    }
+
+    // A rectangle containing the actor, see `BBox::bounds`.  For bullets
+    // it contains the way they went over the last `dt` too, see
+    // `bullet_hits_ship`.
+    pub fn bounds(&self, sspec: &GameSpec, dt: f32) -> Rect {
+        match *self {
+            Actor::Ship(ref ship) =>
+                sspec.get_spec(ship.spec).is_ship().bbox.bounds(ship.trans.pos),
+            Actor::Shooter(ref shooter) => {
+                let spec = sspec.get_spec(shooter.spec).is_shooter();
+                spec.bbox.bounds(shooter.trans.pos)
+            },
+            Actor::Bullet(ref bullet) => {
+                let bbox = &sspec.get_spec(bullet.spec).is_bullet().bbox;
+                let now = bbox.bounds(bullet.trans.pos);
+                let before = bbox.bounds(bullet.trans.pos - bullet.travel(dt));
+                Rect{
+                    pos: Vec2{x: now.pos.x.min(before.pos.x), y: now.pos.y.min(before.pos.y)},
+                    w: now.w + (now.pos.x - before.pos.x).abs(),
+                    h: now.h + (now.pos.y - before.pos.y).abs(),
+                }
+            },
+        }
     }
 
     pub fn is_ship(&self) -> &Ship {
@@ -381,47 +678,197 @@ impl PlayerInput {
     }
 }
 
+#[derive(PartialEq, Clone, Copy, Show, RustcEncodable, RustcDecodable)]
+pub struct Score {
+    pub kills: u32,
+    pub deaths: u32,
+    // Deaths caused by the player itself -- by its own bullets or by
+    // crashing into other ships
+    pub suicides: u32,
+    // Deaths caused by a `Shooter`
+    pub turret_deaths: u32,
+}
+
+impl Score {
+    pub fn new() -> Score {
+        Score{kills: 0, deaths: 0, suicides: 0, turret_deaths: 0}
+    }
+}
+
+#[derive(PartialEq, Clone, Show)]
+pub struct Scores {
+    scores: HashMap<ActorId, Score>,
+}
+
+impl Encodable for Scores {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let len: u32 = self.scores.len() as u32;
+        try!(len.encode(s));
+        for pair in self.scores.iter() {
+            try!(pair.encode(s));
+        }
+        Ok(())
+    }
+}
+
+impl Decodable for Scores {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Scores, D::Error> {
+        let len: u32 = try!(Decodable::decode(d));
+        let len: usize = len as usize;
+        let mut scores = HashMap::new();
+        for _ in range(0, len) {
+            let (player, score) = try!(Decodable::decode(d));
+            let _ = scores.insert(player, score);
+        }
+        Ok(Scores{scores: scores})
+    }
+}
+
+impl Scores {
+    pub fn new() -> Scores {
+        Scores{scores: HashMap::new()}
+    }
+
+    pub fn add_player(&mut self, player: ActorId) {
+        let _ = self.scores.insert(player, Score::new());
+    }
+
+    pub fn remove_player(&mut self, player: ActorId) -> Option<Score> {
+        self.scores.remove(&player)
+    }
+
+    pub fn get(&self, player: ActorId) -> Option<&Score> {
+        self.scores.get(&player)
+    }
+
+    pub fn iter(&self) -> Iter<ActorId, Score> {
+        self.scores.iter()
+    }
+
+    // `actors` is used to find out what the killer was.  If the killer
+    // is gone (e.g. a player who left) only the death is recorded.
+    fn record_death(&mut self, victim: ActorId, killer: Option<ActorId>, actors: &Actors) {
+        match self.scores.get_mut(&victim) {
+            None        => {},
+            Some(score) => score.deaths += 1,
+        };
+        let killer = match killer {
+            None         => return,
+            Some(killer) => killer,
+        };
+        if killer == victim {
+            match self.scores.get_mut(&victim) {
+                None        => {},
+                Some(score) => score.suicides += 1,
+            };
+            return;
+        }
+        match actors.get(killer) {
+            Some(&Actor::Ship(_)) => match self.scores.get_mut(&killer) {
+                None        => {},
+                Some(score) => score.kills += 1,
+            },
+            Some(&Actor::Shooter(_)) => match self.scores.get_mut(&victim) {
+                None        => {},
+                Some(score) => score.turret_deaths += 1,
+            },
+            _ => {},
+        }
+    }
+}
+
 #[derive(PartialEq, Clone, Show, RustcEncodable, RustcDecodable)]
 pub struct Game {
     pub actors: Actors,
+    pub scores: Scores,
+    /// Goes up by one each `advance`
+    pub tick: u32,
     pub time: f32,
 }
 
 impl Game {
-    pub fn new() -> Game {
-        Game{actors: Actors::new(), time: 0.}
+    pub fn new(spec: &GameSpec) -> Game {
+        let mut actors = Actors::new();
+        for turret in spec.map.turrets.iter() {
+            let _ = actors.add(Actor::Shooter(Shooter::new(turret)));
+        };
+        Game{actors: actors, scores: Scores::new(), tick: 0, time: 0.}
+    }
+
+    /// No actors at all, not even the turrets.
+    pub fn empty() -> Game {
+        Game{actors: Actors::new(), scores: Scores::new(), tick: 0, time: 0.}
     }
 
     pub fn advance(&self, spec: &GameSpec, inputs: &Vec<PlayerInput>, dt: f32) -> Game {
+        let history = RingBuf::new();
+        let ticks = HashMap::new();
+        self.advance_lagged(spec, inputs, &Lag{history: &history, ticks: &ticks}, dt)
+    }
+
+    /// Like `advance`, but with the bullets of the players in `lag`
+    /// checked against the ships they were seeing.
+    pub fn advance_lagged(&self, spec: &GameSpec, inputs: &Vec<PlayerInput>, lag: &Lag, dt: f32) -> Game {
         // First move everything, spawn new stuff
         let mut advanced_actors = Actors::prepare_new(&self.actors);
         for (actor_id, actor) in self.actors.iter() {
             let actor_input = PlayerInput::lookup(inputs, *actor_id);
-            match actor.advance(spec, &mut advanced_actors, actor_input, dt) {
+            match actor.advance(spec, &self.actors, &mut advanced_actors, *actor_id, actor_input, dt) {
                 None                 => {},
                 Some(advanced_actor) => { advanced_actors.insert(*actor_id, advanced_actor) },
             }
         };
         
-        // Then compute interactions
+        // Then compute interactions, keeping score of who died.  Only
+        // ships can touch other actors.
+        let mut grid = Grid::new(COLLISION_CELL_SIZE);
+        for (actor_id, actor) in advanced_actors.iter() {
+            let active = match *actor {
+                Actor::Ship(_) => true,
+                _              => false,
+            };
+            grid.insert(*actor_id, actor.bounds(spec, dt), active);
+        };
+        let neighbours = grid.neighbours();
+        let no_neighbours = Vec::new();
+        let lag_hits = LagHits::new(spec, &advanced_actors, lag, dt);
         let mut interacted_actors = Actors::prepare_new(&advanced_actors);
+        let mut scores = self.scores.clone();
         for (actor_id, actor) in advanced_actors.iter() {
-            match actor.interact(spec, &advanced_actors) {
+            let nearby = neighbours.get(actor_id).unwrap_or(&no_neighbours);
+            match actor.interact(spec, &advanced_actors, *actor_id, nearby.as_slice(), &lag_hits, dt) {
                 None                   => {},
-                Some(interacted_actor) => { interacted_actors.insert(*actor_id, interacted_actor) },
+                Some(interacted_actor) => {
+                    match (*actor, interacted_actor) {
+                        (Actor::Ship(ref before), Actor::Ship(ref after)) if before.alive() && !after.alive() =>
+                            scores.record_death(*actor_id, after.killed_by, &advanced_actors),
+                        _ => {},
+                    };
+                    interacted_actors.insert(*actor_id, interacted_actor)
+                },
             }
         };
 
         // Done
         Game{
             actors: interacted_actors,
+            scores: scores,
+            tick: self.tick + 1,
             time: self.time + dt,
         }
     }
 
     pub fn add_ship(&mut self, spec: &GameSpec) -> ActorId {
-        let ship_pos = Vec2 {x: SCREEN_WIDTH/2., y: SCREEN_HEIGHT/2.};
-        self.actors.add(Actor::Ship(Ship::new(spec.ship_spec, ship_pos)))
+        // Cycle through the spawn points as players join
+        let ship_pos = spec.spawn_points[self.actors.len() % spec.spawn_points.len()];
+        let player = self.actors.add(Actor::Ship(Ship::new(spec, spec.ship_spec, ship_pos)));
+        self.scores.add_player(player);
+        player
+    }
+
+    pub fn remove_player(&mut self, player: ActorId) {
+        let _ = self.actors.remove(player);
+        let _ = self.scores.remove_player(player);
     }
 }
 
@@ -445,3 +892,152 @@ impl PlayerGame {
         }
     }
 }
+
+// ---------------------------------------------------------------------
+// Tests
+
+// The game's own spec, compiled into the tests so they don't depend on
+// the directory they run from.
+#[cfg(test)]
+fn test_spec() -> GameSpec {
+    let mut textures = HashMap::new();
+    let _ = textures.insert("planes".to_string(), 0);
+    let _ = textures.insert("background".to_string(), 1);
+    GameSpec::from_json(include_str!("../assets/spec.json"), &textures).ok().unwrap()
+}
+
+#[cfg(test)]
+fn close(a: f32, b: f32) -> bool {
+    (a - b).abs() < 0.001
+}
+
+// Advances `shooter` by a tick, with a ship at `ship_pos` flying at
+// `ship_vel` as the only other actor.
+#[cfg(test)]
+fn advance_shooter(spec: &GameSpec, shooter: &Shooter, ship_pos: Vec2, ship_vel: Vec2) -> Shooter {
+    let mut world = Actors::new();
+    let id = world.add(Actor::Shooter(*shooter));
+    let _ = world.add(Actor::Ship(Ship{
+        vel: ship_vel,
+        ..Ship::new(spec, spec.ship_spec, ship_pos)
+    }));
+    let mut actors = Actors::prepare_new(&world);
+    shooter.advance(spec, &world, &mut actors, id, TIME_STEP).unwrap()
+}
+
+#[test]
+fn test_lead_rotation() {
+    let pos = Vec2{x: 100., y: 100.};
+    let target_pos = Vec2{x: 500., y: -200.};
+    let target_vel = Vec2{x: -150., y: 80.};
+    let rotation = lead_rotation(pos, target_pos, target_vel, 1000.);
+
+    // Both going at constant velocity, the bullet goes through the target
+    let bullet_vel = Vec2{x: 1000., y: 0.}.rotate(rotation);
+    let mut closest = (target_pos - pos).mag();
+    for i in range(0, 20000) {
+        let t = i as f32 / 10000.;
+        let dist = ((target_pos + target_vel * t) - (pos + bullet_vel * t)).mag();
+        if dist < closest { closest = dist };
+    };
+    assert!(closest < 1.);
+
+    // Too fast to catch, we just aim at it
+    let rotation = lead_rotation(pos, target_pos, Vec2{x: 2000., y: 0.}, 1000.);
+    let aim = target_pos - pos;
+    assert!(close(rotation, (-aim.y).atan2(aim.x)));
+}
+
+#[test]
+fn test_shooter_tracking() {
+    let spec = test_spec();
+    let shooter_spec = spec.get_spec(spec.map.turrets[0].spec).is_shooter().clone();
+    let bullet_vel = spec.get_spec(shooter_spec.bullet_spec).is_bullet().vel;
+    assert!(shooter_spec.aim == Aim::Tracking);
+    let mut shooter = Shooter::new(&spec.map.turrets[0]);
+    shooter.trans.rotation = 0.;
+    let pos = shooter.trans.pos;
+
+    // Out of range ships are ignored
+    let far = pos + Vec2{x: 0., y: shooter_spec.detection_radius + 10.};
+    let after = advance_shooter(&spec, &shooter, far, Vec2::zero());
+    assert!(after.trans.rotation == shooter.trans.rotation);
+
+    // In range ones are tracked, turning at most at `rotation_vel`
+    let max_delta = shooter_spec.rotation_vel * TIME_STEP;
+    let mut ship_pos = pos + Vec2{x: 0., y: 400.};
+    let ship_vel = Vec2{x: 100., y: 0.};
+    let mut wanted = 0.;
+    for i in range(0, 100) {
+        wanted = lead_rotation(pos, ship_pos, ship_vel, bullet_vel);
+        let after = advance_shooter(&spec, &shooter, ship_pos, ship_vel);
+        let delta = normalize_angle(after.trans.rotation - shooter.trans.rotation).abs();
+        assert!(delta <= max_delta + 0.0001);
+        // It starts off pointing far away from the ship
+        if i == 0 { assert!(close(delta, max_delta)) };
+        shooter = after;
+        ship_pos = ship_pos + ship_vel * TIME_STEP;
+    };
+    // And end up leading it
+    assert!(close(normalize_angle(shooter.trans.rotation - wanted), 0.));
+}
+
+#[test]
+fn test_bullet_sweep() {
+    let spec = test_spec();
+    let ship_spec = spec.get_spec(spec.ship_spec).is_ship().clone();
+    let bullet_spec = spec.get_spec(ship_spec.bullet_spec).is_bullet().clone();
+    let mut game = Game::empty();
+    let ship_id = game.add_ship(&spec);
+    let ship_pos = match game.actors.get(ship_id) {
+        Some(&Actor::Ship(ref ship)) => ship.trans.pos,
+        _ => unreachable!(),
+    };
+
+    // A tick before the ship, and a tick past it after advancing: it
+    // never overlaps the ship at the end of a tick, but went through it
+    let vel = Vec2{x: bullet_spec.vel, y: 0.};
+    let bullet_id = game.actors.add(Actor::Bullet(Bullet{
+        spec: ship_spec.bullet_spec,
+        owner: ship_id + 1000,
+        trans: Transform{pos: ship_pos - vel * (TIME_STEP / 2.), rotation: 0.},
+        vel: vel,
+        age: TIME_STEP,
+    }));
+    let game = game.advance(&spec, &Vec::new(), TIME_STEP);
+    assert!(game.actors.get(bullet_id).is_none());
+    match game.actors.get(ship_id) {
+        Some(&Actor::Ship(ref ship)) => assert!(close(ship.health, ship_spec.health - bullet_spec.damage)),
+        _ => unreachable!(),
+    };
+}
+
+// A few dozen ships and thousands of bullets flying about, scattered
+// over the map as in the grid tests.
+#[bench]
+fn bench_advance_3000_bullets(b: &mut ::test::Bencher) {
+    let spec = test_spec();
+    let bullet_spec = spec.get_spec(spec.ship_spec).is_ship().bullet_spec;
+    let bullet_vel = spec.get_spec(bullet_spec).is_bullet().vel;
+    let scatter = |&: i: usize| Vec2{
+        x: ((i * 7919) % spec.map.w as usize) as f32,
+        y: ((i * 104729) % spec.map.h as usize) as f32,
+    };
+    let mut game = Game::empty();
+    let ships: Vec<ActorId> = range(0, 40us).map(|i| {
+        let ship = game.add_ship(&spec);
+        game.actors.insert(ship, Actor::Ship(Ship::new(&spec, spec.ship_spec, scatter(i))));
+        ship
+    }).collect();
+    for i in range(0, 3000us) {
+        let rotation = i as f32;
+        let _ = game.actors.add(Actor::Bullet(Bullet{
+            spec: bullet_spec,
+            owner: ships[i % ships.len()],
+            trans: Transform{pos: scatter(ships.len() + i), rotation: rotation},
+            vel: Vec2{x: bullet_vel, y: 0.}.rotate(rotation),
+            age: TIME_STEP,
+        }));
+    }
+    b.iter(|| game.advance(&spec, &Vec::new(), TIME_STEP));
+}
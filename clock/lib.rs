@@ -0,0 +1,114 @@
+#![allow(unstable)]
+extern crate time;
+
+use std::io::timer;
+use std::time::Duration;
+use std::sync::{Arc, Mutex, Condvar};
+
+/// Where the server and the network get the time from, so that they
+/// don't need SDL for it, and so that tests can decide how it goes.
+/// Clones share the time.
+#[derive(Clone)]
+pub enum Clock {
+    /// The real, monotonic, time, from when the clock was made
+    Real(u64),
+    /// Time goes on only when `advance` is called
+    Manual(Arc<(Mutex<u32>, Condvar)>),
+}
+
+impl Clock {
+    pub fn real() -> Clock {
+        Clock::Real(time::precise_time_ns())
+    }
+
+    /// Starts at 0.
+    pub fn manual() -> Clock {
+        Clock::Manual(Arc::new((Mutex::new(0), Condvar::new())))
+    }
+
+    /// Milliseconds since the clock was made, like `sdl2::get_ticks`.
+    pub fn ticks(&self) -> u32 {
+        match *self {
+            Clock::Real(started) => ((time::precise_time_ns() - started) / 1000000) as u32,
+            Clock::Manual(ref manual) => *manual.0.lock().unwrap(),
+        }
+    }
+
+    /// With a manual clock, blocks until somebody else moves it forward
+    /// by `ms`.
+    pub fn delay(&self, ms: u32) {
+        match *self {
+            Clock::Real(_)   => timer::sleep(Duration::milliseconds(ms as i64)),
+            Clock::Manual(_) => self.delay_until(self.ticks() + ms),
+        }
+    }
+
+    /// Blocks until the clock gets to `ticks`, if it isn't there already.
+    pub fn delay_until(&self, ticks: u32) {
+        match *self {
+            Clock::Real(_) => {
+                let now = self.ticks();
+                if ticks > now { self.delay(ticks - now) };
+            },
+            Clock::Manual(ref manual) => {
+                let (ref current, ref cvar) = **manual;
+                let mut now = current.lock().unwrap();
+                while *now < ticks {
+                    now = cvar.wait(now).unwrap();
+                }
+            },
+        }
+    }
+
+    /// Moves a manual clock forward, waking up whoever is waiting on it.
+    /// Real clocks can't be moved.
+    pub fn advance(&self, ms: u32) {
+        match *self {
+            Clock::Real(_) => panic!("clock::Clock::advance: the real clock can't be moved"),
+            Clock::Manual(ref manual) => {
+                let (ref ticks, ref cvar) = **manual;
+                *ticks.lock().unwrap() += ms;
+                cvar.notify_all();
+            },
+        }
+    }
+}
+
+// ---------------------------------------------------------------------
+// Tests
+
+#[test]
+fn test_manual() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::Thread;
+
+    let clock = Clock::manual();
+    assert!(clock.ticks() == 0);
+    clock.advance(10);
+    assert!(clock.ticks() == 10);
+
+    // Somebody else has to move it for the delay to end
+    let done = Arc::new(AtomicBool::new(false));
+    let (mover, mover_done) = (clock.clone(), done.clone());
+    let _guard = Thread::scoped(move || {
+        while !mover_done.load(Ordering::SeqCst) {
+            mover.advance(1);
+            timer::sleep(Duration::milliseconds(1));
+        }
+    });
+    clock.delay(20);
+    assert!(clock.ticks() >= 30);
+    clock.delay_until(40);
+    done.store(true, Ordering::SeqCst);
+    assert!(clock.ticks() >= 40);
+    // Already there
+    clock.delay_until(0);
+}
+
+#[test]
+fn test_real() {
+    let clock = Clock::real();
+    let before = clock.ticks();
+    clock.delay(10);
+    assert!(clock.ticks() - before >= 10);
+}
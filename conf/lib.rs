@@ -4,6 +4,8 @@ pub static SCREEN_HEIGHT: f32 = 600.;
 // 50 ms timesteps
 pub const TIME_STEP: f32 = 0.05;
 pub const MAX_FRAME_TIME: f32 = 0.250;
+// Side of the cells used to find colliding actors
+pub const COLLISION_CELL_SIZE: f32 = 100.;
 
 // 10s timeout
 pub const CONN_TIMEOUT: u32 = 10000;
@@ -11,3 +13,52 @@ pub const PROTO_ID: u32 = 0xD05F1575;
 pub const MAX_PACKET_SIZE: usize = 1400;
 // 1s ping interval
 pub const PING_INTERVAL: u32 = 1000;
+// Clients give up connecting after 5s, asking again every 250ms
+pub const CONNECT_TIMEOUT: u32 = 5000;
+pub const CONNECT_RETRY: u32 = 250;
+pub const MAX_CLIENTS: usize = 32;
+// Longest player names and chat lines, in characters
+pub const MAX_TEXT_LEN: usize = 80;
+// Clients we're willing to have halfway through the handshake
+pub const MAX_PENDING_CONNS: usize = 64;
+pub const DISCONNECT_REPEATS: usize = 3;
+// Reliable messages are sent again if not acked within 200ms
+pub const RESEND_INTERVAL: u32 = 200;
+// How far ahead of the one we're waiting for a reliable message can be
+pub const MAX_RELIABLE_EARLY: u32 = 1024;
+// Bigger bodies are split in pieces of this size, leaving room for the
+// header in `MAX_PACKET_SIZE`.
+pub const FRAGMENT_SIZE: usize = 1200;
+pub const MAX_FRAGMENTS: usize = 256;
+// Fragmented bodies we're willing to be putting together at once, for
+// each connection
+pub const MAX_PARTIALS: usize = 16;
+// Give up on fragmented bodies not completed within 1s
+pub const FRAGMENT_TIMEOUT: u32 = 1000;
+// Games kept around by each side to take snapshot deltas against
+pub const SNAPSHOT_HISTORY: usize = 32;
+// Inputs the client keeps around to replay on top of the server's games
+pub const MAX_PENDING_INPUTS: usize = 64;
+// The server drops inputs meant for more than a second after its
+// current tick
+pub const MAX_INPUT_AHEAD: u32 = 20;
+// Corrections to the predicted ship of more than 100px are made straight
+// away, smaller ones shrink by a fifth with each frame
+pub const MAX_CORRECTION: f32 = 100.;
+pub const CORRECTION_DECAY: f32 = 0.8;
+// Clients draw the game 100ms behind the server, so that they
+// usually have a game after the one they draw to interpolate to
+pub const RENDER_DELAY: f32 = 0.1;
+pub const GAME_BUFFER_SIZE: usize = 32;
+// ~60 frames per second
+pub const FRAME_INTERVAL: u32 = 16;
+// When the next game is late clients move the last one forward on their
+// own, but for no more than 250ms
+pub const MAX_EXTRAPOLATION: f32 = 0.25;
+// Bullets of lagging players are checked against where ships were up
+// to 250ms ago
+pub const MAX_REWIND: f32 = 0.25;
+// Packets we keep track of to measure round trips and losses, and how
+// much each new measurement counts
+pub const MAX_IN_FLIGHT: usize = 256;
+pub const STATS_SMOOTHING: f32 = 0.1;
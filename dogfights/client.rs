@@ -0,0 +1,255 @@
+// The SDL client: reading the keyboard, and drawing the games the
+// server sends along with the HUD.
+
+extern crate sdl2;
+
+use std::sync::mpsc::{channel, TryRecvError};
+use std::cmp::min;
+use std::sync::{Arc, Mutex};
+use std::thread::Thread;
+
+use actors::*;
+use specs::*;
+use conf::*;
+use input::*;
+use server::*;
+use network;
+use interpolate::GameBuffer;
+
+// ---------------------------------------------------------------------
+// Keyboard
+
+// While the player is typing key presses go to `text`, but
+// releasing keys still works so that nothing is stuck.  `stats` is
+// whether the network stats are shown, which only the client cares
+// about.
+pub fn process_events(input: Input, text: &mut TextEntry, stats: &mut bool) -> Input {
+    let mut input = input;
+    loop {
+        match sdl2::event::poll_event() {
+            sdl2::event::Event::None =>
+                break,
+            sdl2::event::Event::Quit(_) =>
+                input.quit = true,
+            sdl2::event::Event::KeyDown(_, _, key, _, _, _) if text.typing.is_some() =>
+                text.key(key),
+            sdl2::event::Event::KeyDown(_, _, key, _, _, _) =>
+                match key {
+                    sdl2::keycode::KeyCode::Return => text.typing = Some(String::new()),
+                    sdl2::keycode::KeyCode::Left  => input.rotating = Rotating::Left,
+                    sdl2::keycode::KeyCode::Right => input.rotating = Rotating::Right,
+                    sdl2::keycode::KeyCode::Up    => input.accel = true,
+                    sdl2::keycode::KeyCode::X     => input.firing = true,
+                    sdl2::keycode::KeyCode::P     => input.paused = !input.paused,
+                    sdl2::keycode::KeyCode::F3    => *stats = !*stats,
+                    _                             => {},
+                },
+            sdl2::event::Event::KeyUp(_, _, key, _, _, _) => {
+                if input.accel && key == sdl2::keycode::KeyCode::Up {
+                    input.accel = false
+                };
+                if input.firing && key == sdl2::keycode::KeyCode::X {
+                    input.firing = false;
+                };
+                if input.rotating == Rotating::Left && key == sdl2::keycode::KeyCode::Left {
+                    input.rotating = Rotating::Still;
+                };
+                if input.rotating == Rotating::Right && key == sdl2::keycode::KeyCode::Right {
+                    input.rotating = Rotating::Still;
+                };
+            },
+            _ => {},
+        }
+    };
+    input
+}
+
+// ---------------------------------------------------------------------
+// Text entry, for the chat
+
+pub struct TextEntry {
+    // What the player is typing, `None` if they aren't
+    pub typing: Option<String>,
+    // Lines the player has finished typing, to be taken by whoever is
+    // interested.
+    pub entered: Vec<String>,
+}
+
+impl TextEntry {
+    pub fn new() -> TextEntry {
+        TextEntry{typing: None, entered: Vec::new()}
+    }
+
+    fn key(&mut self, key: sdl2::keycode::KeyCode) {
+        let mut typing = match self.typing.take() {
+            None         => return,
+            Some(typing) => typing,
+        };
+        match key {
+            sdl2::keycode::KeyCode::Return => {
+                if !typing.is_empty() { self.entered.push(typing) };
+                return;
+            },
+            sdl2::keycode::KeyCode::Escape =>
+                return,
+            sdl2::keycode::KeyCode::Backspace => {
+                let _ = typing.pop();
+            },
+            _ => {
+                // Printable keys have their ASCII code
+                let code = key as i32;
+                if code >= 32 && code < 127 && typing.len() < MAX_TEXT_LEN {
+                    typing.push(code as u8 as char);
+                }
+            },
+        };
+        self.typing = Some(typing);
+    }
+}
+
+// ---------------------------------------------------------------------
+// Drawing
+
+// How many chat lines the SDL client shows
+const CHAT_LINES: usize = 5;
+
+/// What the SDL client draws on top of the game
+pub struct Hud {
+    /// The last chat lines
+    pub chat: Vec<String>,
+    /// What the player is typing, if anything
+    pub typing: Option<String>,
+    /// The network stats, if the player wants to see them
+    pub stats: Option<Vec<String>>,
+}
+
+fn stats_lines(stats: &network::Stats) -> Vec<String> {
+    vec![
+        format!("rtt {:.0}ms", stats.rtt),
+        format!("jitter {:.0}ms", stats.jitter),
+        format!("loss {:.1}%", stats.loss * 100.),
+        format!("sent {}kb", stats.bytes_sent / 1024),
+        format!("received {}kb", stats.bytes_received / 1024),
+    ]
+}
+
+/// The game is drawn `RENDER_DELAY` behind the server, interpolating
+/// between the games we got, about every `FRAME_INTERVAL`.  Our own
+/// ship is drawn where it will be once the server applies the inputs we
+/// sent, rather than where the last game we got says it is.
+pub fn attach_sdl<S: ClientSend + Send + Clone, R: ClientRecv + Send + 'static, F: Fn(PlayerGame, &Hud)>(send: &S, recv: R, spec: &GameSpec, on_game_update: F) {
+    let (quit_tx, quit_rx) = channel();
+    let mut worker_send = send.clone();
+    let typing: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+    let worker_typing = typing.clone();
+    let show_stats = Arc::new(Mutex::new(false));
+    let worker_show_stats = show_stats.clone();
+    let predictor = Arc::new(Mutex::new(Predictor::new()));
+    let worker_predictor = predictor.clone();
+
+    // Thread sending inputs
+    let _ = Thread::spawn(move || {
+        // Check for input every 5ms.  We send it when it changes, and
+        // once per server tick anyway so that the prediction has
+        // something to go on.
+        let input_interval = (TIME_STEP * 1000.) as u32;
+        let mut input = Input::new();
+        let mut text = TextEntry::new();
+        let mut stats = false;
+        let mut last_sent = sdl2::get_ticks();
+        let (seq, tick) = worker_predictor.lock().unwrap().input(input, last_sent, 0.);
+        let _ = worker_send.send_input(seq, tick, input);
+        loop {
+            let new_input = process_events(input, &mut text, &mut stats);
+            if new_input.quit {
+                let _ = quit_tx.send(());
+                break
+            }
+            let now = sdl2::get_ticks();
+            if new_input != input || now - last_sent >= input_interval {
+                input = new_input;
+                last_sent = now;
+                let rtt = worker_send.stats().map_or(0., |stats| stats.rtt);
+                let (seq, tick) = worker_predictor.lock().unwrap().input(input, now, rtt);
+                let alive = worker_send.send_input(seq, tick, input);
+                if !alive { break };
+            }
+            let mut alive = true;
+            for line in text.entered.drain() {
+                alive = alive && worker_send.send_chat(line);
+            }
+            if !alive { break };
+            *worker_typing.lock().unwrap() = text.typing.clone();
+            *worker_show_stats.lock().unwrap() = stats;
+            sdl2::timer::delay(5);
+        }
+    });
+
+    // Thread getting the messages, so that we don't draw only when
+    // one arrives.  We don't wait for it when quitting, it stops by
+    // itself once the next message finds us gone.
+    let (msgs_tx, msgs_rx) = channel();
+    let _ = Thread::spawn(move || {
+        let mut recv = recv;
+        loop {
+            match recv.recv_msg() {
+                None => break,
+                Some(msg) => if msgs_tx.send(msg).is_err() { break },
+            }
+        }
+    });
+
+    // Get the game and draw
+    let mut hud = Hud{chat: Vec::new(), typing: None, stats: None};
+    let mut games = GameBuffer::new();
+    let mut latest: Option<(PlayerGame, Option<u32>)> = None;
+    'draw: loop {
+        let frame_begin = sdl2::get_ticks();
+        let quit = quit_rx.try_recv();
+        match quit {
+            Ok(()) => break,
+            Err(TryRecvError::Empty) => (),
+            Err(TryRecvError::Disconnected) => break,
+        }
+
+        loop {
+            match msgs_rx.try_recv() {
+                Ok(ServerMsg::Chat(line)) => {
+                    if hud.chat.len() >= CHAT_LINES {
+                        let _ = hud.chat.remove(0);
+                    };
+                    hud.chat.push(format!("{}: {}", line.from, line.text));
+                },
+                Ok(ServerMsg::Game(game, last_input)) => {
+                    predictor.lock().unwrap().received(game.game.tick, frame_begin);
+                    games.push(game.game.clone(), frame_begin);
+                    latest = Some((game, last_input));
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break 'draw,
+            }
+        }
+
+        match latest {
+            None => (),
+            Some((ref latest_game, last_input)) => {
+                let predicted = predictor.lock().unwrap().predict(spec, latest_game, last_input, frame_begin);
+                let mut game = games.game_at(spec, frame_begin).unwrap();
+                match predicted.game.actors.get(predicted.player) {
+                    None       => (),
+                    Some(ship) => game.actors.insert(predicted.player, *ship),
+                };
+                hud.typing = typing.lock().unwrap().clone();
+                hud.stats = if *show_stats.lock().unwrap() {
+                    send.stats().map(|stats| stats_lines(&stats))
+                } else {
+                    None
+                };
+                on_game_update(PlayerGame{player: predicted.player, game: Arc::new(game)}, &hud);
+            },
+        };
+
+        let frame_end = sdl2::get_ticks();
+        sdl2::timer::delay((FRAME_INTERVAL - min(FRAME_INTERVAL, frame_end - frame_begin)) as usize);
+    };
+}
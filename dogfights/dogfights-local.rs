@@ -2,13 +2,14 @@
 extern crate dogfights;
 extern crate getopts;
 
-use getopts::{optmulti, getopts};
+use getopts::{optmulti, optopt, getopts};
 
 fn main() {
     let args = std::os::args();
 
     let opts = &[
         optmulti("", "ai", "Add an AI to the game", "AI"),
+        optopt("", "spec", "The game spec to use", "FILE"),
     ];
     let matches = match getopts(args.tail(), opts) {
         Ok(m) => m,
@@ -16,6 +17,7 @@ fn main() {
     };
 
     let ais: Vec<String> = matches.opt_strs("ai");
+    let spec_file = matches.opt_str("spec").unwrap_or(dogfights::DEFAULT_SPEC_FILE.to_string());
 
-    dogfights::run_local(ais)
+    dogfights::run_local(ais, spec_file.as_slice())
 }
@@ -17,6 +17,7 @@ fn main() {
         optopt("s", "server", "Server to connect to", "ADDRESS"),
         optopt("p", "port", "The port to bind to", "PORT"),
         optopt("", "ai", "AI to use", "AI"),
+        optopt("", "spec", "The game spec to use", "FILE"),
         optflag("x", "display", "Whether to show a display or not")
     ];
     let matches = match getopts(args.tail(), opts) {
@@ -39,5 +40,6 @@ fn main() {
         Some(s) => s,
     };
     let display = matches.opt_present("x");
-    dogfights::run_remote_ai(&*server, ("127.0.0.1", port), &*ai_s, display)
+    let spec_file = matches.opt_str("spec").unwrap_or(dogfights::DEFAULT_SPEC_FILE.to_string());
+    dogfights::run_remote_ai(&*server, ("127.0.0.1", port), &*ai_s, display, &*spec_file)
 }
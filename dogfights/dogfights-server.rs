@@ -15,6 +15,9 @@ fn main() {
 
     let opts = &[
         optopt("p", "port", "The port to bind to", "PORT"),
+        optopt("", "spec", "The game spec to use", "FILE"),
+        optopt("", "link-out", "Conditions for the packets we send, e.g. latency=100,jitter=20,loss=0.05,duplicate=0.01,reorder=0.01", "CONDITIONS"),
+        optopt("", "link-in", "Conditions for the packets we receive, like --link-out", "CONDITIONS"),
         optflag("x", "display", "Whether to show a display or not")
     ];
     let matches = match getopts(args.tail(), opts) {
@@ -29,5 +32,10 @@ fn main() {
         },
     };
     let display = matches.opt_present("x");
-    dogfights::run_server(("127.0.0.1", port));
+    let link = match dogfights::parse_link(matches.opt_str("link-out"), matches.opt_str("link-in")) {
+        None       => {print_usage(program, opts); return;}
+        Some(link) => link,
+    };
+    let spec_file = matches.opt_str("spec").unwrap_or(dogfights::DEFAULT_SPEC_FILE.to_string());
+    dogfights::run_server(("127.0.0.1", port), spec_file.as_slice(), link);
 }
@@ -15,6 +15,7 @@ extern crate conf;
 extern crate specs;
 extern crate network;
 extern crate ai;
+extern crate interpolate;
 
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
@@ -24,93 +25,156 @@ use std::thread::Thread;
 use std::ops::Deref;
 use std::sync::{Arc, Mutex};
 use std::io::{IoErrorKind};
+use std::str::FromStr;
 
 use actors::*;
 use input::*;
 use render::*;
 use init::*;
+use client::*;
 use server::*;
 
 mod init;
+mod client;
 
-pub fn run_local(ais: Vec<String>) {
+pub const DEFAULT_SPEC_FILE: &'static str = "assets/spec.json";
+
+// Draws what goes on top of the game, and shows the frame.
+fn draw_hud(render: &RenderEnv, hud: &Hud) {
+    render.chat(hud.chat.as_slice(), hud.typing.as_ref().map(|s| s.as_slice())).ok().unwrap();
+    match hud.stats {
+        None            => (),
+        Some(ref stats) => render.stats(stats.as_slice()).ok().unwrap(),
+    };
+    render.renderer.present();
+}
+
+pub fn run_local(ais: Vec<String>, spec_file: &str) {
     let renderer = init_sdl(false);
     let textures = init_textures(&renderer);
     let render = RenderEnv{renderer: renderer, textures: textures};
-    let spec = Arc::new(init_spec());
-    let server = Server::new(spec.clone(), Game::new());
-    let (player, mut client_send, mut client_recv) = server.join_handle().join();
+    let spec = Arc::new(init_spec(spec_file));
+    let server = Server::new(spec.clone(), Game::new(spec.deref()));
+    let (player, mut client_send, client_recv) = server.join_handle().join(None);
 
     // Add ais
     for ai_s in ais.iter() {
         let ai = ai::parse_ai_string(&**ai_s, Some(player));
-        let (_, mut ai_send, mut ai_recv) = server.join_handle().join();
+        let (_, mut ai_send, mut ai_recv) = server.join_handle().join(Some(ai_s.clone()));
         let _ = Thread::spawn(move || { attach_ai(&mut ai_send, &mut ai_recv, ai.deref(), |_| {}) });
     }
 
     // Thread running the server
     let _ = Thread::spawn(move || { server.run(); });
 
-    attach_sdl(&mut client_send, &mut client_recv, |game| {
+    attach_sdl(&mut client_send, client_recv, spec.deref(), |game, hud| {
         render.player_game(&game, spec.deref()).ok().unwrap();
-        render.renderer.present();
+        draw_hud(&render, hud);
     });
 }
 
-fn should_quit() -> bool {
-    loop {
-        match sdl2::event::poll_event() {
-            sdl2::event::Event::None    => break,
-            sdl2::event::Event::Quit(_) => return true,
-            _                           => (),
-        }
+// `false` if the player is gone
+fn forward(send: &mut ServerClientSend, msg: ClientMsg) -> bool {
+    match msg {
+        ClientMsg::Input(seq, tick, input) => send.send_input(seq, tick, input),
+        ClientMsg::Chat(text)        => send.send_chat(text),
+        // Already joined
+        ClientMsg::Join(_)           => true,
+        // Taken care of by the `SnapshotEncoder`
+        ClientMsg::Ack(_)            => true,
+    }
+}
+
+/// The link conditions given on the command line, `None` if they don't
+/// parse.  See `network::Conditions` for the format.
+pub fn parse_link(outgoing: Option<String>, incoming: Option<String>) -> Option<network::Link> {
+    let parse = |conditions: Option<String>| match conditions {
+        None             => Some(network::Conditions::perfect()),
+        Some(conditions) => FromStr::from_str(conditions.as_slice()),
     };
-    false
+    match (parse(outgoing), parse(incoming)) {
+        (Some(outgoing), Some(incoming)) => Some(network::Link{outgoing: outgoing, incoming: incoming}),
+        _                                => None,
+    }
 }
 
-pub fn run_server<A: ToSocketAddr>(addr: A) {
-    let mut net = network::Server::new(addr).ok().unwrap();
-    init_headless_sdl();
-    let spec = Arc::new(init_spec());
-    let server = Server::new(spec.clone(), Game::new());
+pub fn run_server<A: ToSocketAddr>(addr: A, spec_file: &str, link: network::Link) {
+    let mut net = network::Server::conditioned(addr, conf::MAX_CLIENTS, link).ok().unwrap();
+    let spec = Arc::new(init_spec(spec_file));
+    let server = Server::new(spec.clone(), Game::new(spec.deref()));
     let join_handle = server.join_handle();
 
-    let clients: Arc<Mutex<HashMap<SocketAddr, ServerClientSend>>> = Arc::new(Mutex::new(HashMap::new()));
-    let worker_clients = clients.clone();
+    let clients: Arc<Mutex<HashMap<SocketAddr, (ServerClientSend, Arc<Mutex<SnapshotEncoder>>)>>> = Arc::new(Mutex::new(HashMap::new()));
 
     // Thread running the server
     let _ = Thread::spawn(move || { server.run(); });
 
     loop {
-        if should_quit() { break };
-
-        let (addr, input): (SocketAddr, Input) = net.recv().ok().unwrap();
+        let event: network::Event<ClientMsg> = net.recv().ok().unwrap();
+        let (addr, msg) = match event {
+            network::Event::Connect(addr) => {
+                info!("New connection {}", addr);
+                continue;
+            },
+            network::Event::Disconnect(addr, reason) => {
+                info!("Connection {} closed: {:?}", addr, reason);
+                match clients.lock().unwrap().remove(&addr) {
+                    None       => (),
+                    Some((send, _)) => send.leave(),
+                };
+                continue;
+            },
+            network::Event::Message(addr, msg) => (addr, msg),
+        };
         match clients.lock().unwrap().entry(addr) {
-            Entry::Occupied(mut entry) => {
-                entry.get_mut().send_input(input);
+            Entry::Occupied(mut entry) => match msg {
+                ClientMsg::Ack(id) => {
+                    let &mut (ref mut send, ref encoder) = entry.get_mut();
+                    let lag = {
+                        let mut encoder = encoder.lock().unwrap();
+                        encoder.ack(id);
+                        encoder.lag()
+                    };
+                    match lag {
+                        None      => (),
+                        // On top of that they draw the game a bit behind
+                        // the last one they got
+                        Some(lag) => { let _ = send.set_lag(lag + conf::RENDER_DELAY); },
+                    }
+                },
+                msg => { let _ = forward(&mut entry.get_mut().0, msg); },
             },
             Entry::Vacant(entry) => {
-                let (player, mut player_send, mut player_recv) = join_handle.join();
+                // Clients are supposed to start with `Join`, but if
+                // that got lost we let them in anyway.
+                let name = match msg {
+                    ClientMsg::Join(ref name) => name.clone(),
+                    _                         => None,
+                };
+                let (player, mut player_send, mut player_recv) = join_handle.join(name);
                 info!("New player {} for connection {}", player, addr);
-                let _ = entry.insert(player_send.clone());
+                let encoder = Arc::new(Mutex::new(SnapshotEncoder::new()));
+                let _ = entry.insert((player_send.clone(), encoder.clone()));
                 let mut worker_net = net.clone();
-                let clients = worker_clients.clone();
                 let _ = Thread::spawn(move || {
                     loop {
-                        // It it was error, it'd mean that the server
-                        // has removed the player, for some reason
-                        let mb_game = player_recv.recv_game();
-                        match mb_game {
-                            None => break,
-                            Some(game) => {
-                                let send_res = worker_net.send(addr, &game);
+                        // `None` means that the player has left, or that
+                        // the server has removed them.
+                        let mb_msg = player_recv.recv_msg();
+                        match mb_msg {
+                            None => {
+                                // Fails if the client is already gone
+                                let _ = worker_net.disconnect(addr);
+                                break
+                            },
+                            Some(msg) => {
+                                let msg = encoder.lock().unwrap().encode(msg);
+                                let send_res = worker_net.send(addr, &msg, msg.channel());
                                 match send_res {
                                     Ok(()) => (),
                                     Err(err) => match err.kind {
-                                        IoErrorKind::Closed => {
-                                            let _ = clients.lock().unwrap().remove(&addr);
-                                            break
-                                        },
+                                        // `net.recv` will tell us about it
+                                        IoErrorKind::Closed | IoErrorKind::NotConnected => break,
                                         _ => (), // Just ignore it
                                     }
                                 };
@@ -118,32 +182,42 @@ pub fn run_server<A: ToSocketAddr>(addr: A) {
                         }
                     }
                 });
-                let _ = player_send.send_input(input);
+                let _ = forward(&mut player_send, msg);
             }
         };
     }
 }
 
-pub fn run_remote<A: ToSocketAddr, B: ToSocketAddr>(server_addr: A, bind: B) {
-    let client = network::Client::new(server_addr, bind, true).ok().unwrap();
+pub fn run_remote<A: ToSocketAddr, B: ToSocketAddr>(server_addr: A, bind: B, spec_file: &str, name: Option<String>, link: network::Link) {
+    let client = match network::Client::conditioned(server_addr, bind, true, link) {
+        Ok(client) => client,
+        Err(err)   => panic!("Could not connect to the server: {}", err),
+    };
     let mut client_handle_send = client.handle();
-    let mut client_handle_recv = client.handle();
+    let client_recv = RemoteRecv::new(client.handle());
+    let join = ClientMsg::Join(name);
+    client_handle_send.send(&join, join.channel()).ok().unwrap();
 
     let renderer = init_sdl(false);
     let textures = init_textures(&renderer);
     let render = RenderEnv{renderer: renderer, textures: textures};
-    let spec = Arc::new(init_spec());
+    let spec = Arc::new(init_spec(spec_file));
 
-    attach_sdl(&mut client_handle_send, &mut client_handle_recv, |game| {
+    attach_sdl(&mut client_handle_send, client_recv, spec.deref(), |game, hud| {
         render.player_game(&game, spec.deref()).ok().unwrap();
-        render.renderer.present();
+        draw_hud(&render, hud);
     });
 }
 
-pub fn run_remote_ai<A: ToSocketAddr, B: ToSocketAddr>(server_addr: A, bind: B, ai_s: &str, display: bool) {
-    let client = network::Client::new(server_addr, bind, true).ok().unwrap();
+pub fn run_remote_ai<A: ToSocketAddr, B: ToSocketAddr>(server_addr: A, bind: B, ai_s: &str, display: bool, spec_file: &str) {
+    let client = match network::Client::new(server_addr, bind, true) {
+        Ok(client) => client,
+        Err(err)   => panic!("Could not connect to the server: {}", err),
+    };
     let mut client_handle_send = client.handle();
-    let mut client_handle_recv = client.handle();
+    let mut client_recv = RemoteRecv::new(client.handle());
+    let join = ClientMsg::Join(Some(ai_s.to_string()));
+    client_handle_send.send(&join, join.channel()).ok().unwrap();
 
     let ai = ai::parse_ai_string(ai_s, None);
 
@@ -153,13 +227,12 @@ pub fn run_remote_ai<A: ToSocketAddr, B: ToSocketAddr>(server_addr: A, bind: B,
         let render = RenderEnv{renderer: renderer, textures: textures};
         Some(render)
     } else {
-        init_headless_sdl();
         None
     };
 
-    let spec = init_spec();
+    let spec = init_spec(spec_file);
 
-    attach_ai(&mut client_handle_send, &mut client_handle_recv, ai.deref(), |player_game| {
+    attach_ai(&mut client_handle_send, &mut client_recv, ai.deref(), |player_game| {
         match mb_render {
             None => (),
             Some(ref render) => {
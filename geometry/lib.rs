@@ -1,5 +1,4 @@
 #![allow(unstable)]
-extern crate sdl2;
 extern crate "rustc-serialize" as rustc_serialize;
 
 use std::num::Float;
@@ -19,6 +18,26 @@ pub fn from_radians(x: f32) -> f32 {
     x * 180./PI
 }
 
+// Brings the angle in the (-PI, PI] range.
+#[inline]
+pub fn normalize_angle(x: f32) -> f32 {
+    let x = x % (2.*PI);
+    if x > PI {
+        x - 2.*PI
+    } else if x <= -PI {
+        x + 2.*PI
+    } else {
+        x
+    }
+}
+
+// Goes from `before` to `after` the shortest way round, ending up in
+// the (-PI, PI] range.
+#[inline]
+pub fn interpolate_angle(before: f32, after: f32, alpha: f32) -> f32 {
+    normalize_angle(before + normalize_angle(after - before) * alpha)
+}
+
 // ---------------------------------------------------------------------
 // Transform
 
@@ -120,11 +139,6 @@ impl Div<f32> for Vec2 {
 }
 
 impl Vec2 {
-    #[inline]
-    pub fn point(self) -> sdl2::rect::Point {
-        sdl2::rect::Point{x: self.x as i32, y: self.y as i32}
-    }
-
     // pub fn rotate_centered(&self, center: &Vec2, rotation: f32) -> Vec2 {
     //     let x_diff = self.x - center.x;
     //     let y_diff = self.y - center.y;
@@ -163,6 +177,11 @@ impl Vec2 {
     pub fn norm(self) -> Vec2 {
         self / self.mag()
     }
+
+    #[inline]
+    pub fn dot(self, other: Vec2) -> f32 {
+        self.x*other.x + self.y*other.y
+    }
 }
 
 // ---------------------------------------------------------------------
@@ -187,15 +206,6 @@ fn max(x: f32, y: f32) -> f32 {
 }
  
 impl Rect {
-    pub fn sdl_rect(&self) -> sdl2::rect::Rect {
-        sdl2::rect::Rect {
-            x: self.pos.x as i32,
-            y: self.pos.y as i32,
-            w: self.w as i32,
-            h: self.h as i32,
-        }
-    }
-
     #[inline(always)]
     pub fn transform(&self, trans: &Transform) -> (Vec2, Vec2, Vec2, Vec2) {
         (self.pos.transform(trans),
@@ -281,3 +291,26 @@ fn test_overlapping() {
         &rect_1, &Transform{pos: Vec2{x: 1.51, y: 0.}, rotation: to_radians(-30.)},
         &rect_2, &Transform{pos: Vec2{x: 0., y: 0.}, rotation: to_radians(-30.)}));
 }
+
+#[cfg(test)]
+fn close(x: f32, y: f32) -> bool {
+    (x - y).abs() < 0.0001
+}
+
+#[test]
+fn test_angles() {
+    // PI and -PI are the same, and floats can land on either side
+    assert!(close(normalize_angle(3.*PI).abs(), PI));
+    assert!(close(normalize_angle(-PI), PI));
+    assert!(close(normalize_angle(2.*PI + 0.5), 0.5));
+    assert!(close(normalize_angle(-2.*PI - 0.5), -0.5));
+
+    // Across PI, rather than through 0
+    let almost = PI - 0.1;
+    assert!(close(interpolate_angle(almost, -almost, 0.5).abs(), PI));
+    assert!(close(interpolate_angle(almost, -almost, 0.25), PI - 0.05));
+    assert!(close(interpolate_angle(-almost, almost, 0.25), -PI + 0.05));
+    // Same angle, one of them wound up
+    assert!(close(interpolate_angle(0.5, 0.5 + 4.*PI, 0.5), 0.5));
+    assert!(close(interpolate_angle(0., 1., 0.5), 0.5));
+}
@@ -0,0 +1,112 @@
+// Games kept as they arrive from the server, so that we can draw them a
+// fixed delay behind it, in between two of them, rather than whenever
+// one happens to arrive.
+
+use std::collections::RingBuf;
+use std::sync::Arc;
+
+use actors::*;
+use specs::*;
+use conf::*;
+
+use interpolate::interpolate_game;
+use extrapolate::extrapolate_game;
+
+#[cfg(test)] use std::num::Float;
+
+// How quickly we follow the server's clock when games start arriving
+// later than they used to.
+const OFFSET_DRIFT: f32 = 0.01;
+
+pub struct GameBuffer {
+    /// Oldest first
+    games: RingBuf<Arc<Game>>,
+    /// The server's `Game::time` minus our clock, in seconds
+    offset: Option<f32>,
+}
+
+impl GameBuffer {
+    pub fn new() -> GameBuffer {
+        GameBuffer{games: RingBuf::new(), offset: None}
+    }
+
+    /// `now` is in milliseconds, as given by `sdl2::get_ticks`.  Games
+    /// older than the last one we got are dropped.
+    pub fn push(&mut self, game: Arc<Game>, now: u32) {
+        match self.games.back() {
+            Some(last) if game.time <= last.time => return,
+            _ => (),
+        };
+        // The game which took the least to get here tells us best
+        // where the server's clock is, but we don't want to stick to
+        // that forever in case the server slows down.
+        let offset = game.time - (now as f32) / 1000.;
+        self.offset = Some(match self.offset {
+            Some(old) if offset < old => old + (offset - old) * OFFSET_DRIFT,
+            _                         => offset,
+        });
+        self.games.push_back(game);
+        if self.games.len() > GAME_BUFFER_SIZE {
+            let _ = self.games.pop_front();
+        }
+    }
+
+    /// The game `RENDER_DELAY` behind the server at `now`.  If we don't
+    /// have games that far ahead we extrapolate from the last one, and
+    /// if we don't have games that far behind we get the first.
+    pub fn game_at(&self, spec: &GameSpec, now: u32) -> Option<Game> {
+        let offset = match self.offset {
+            None         => return None,
+            Some(offset) => offset,
+        };
+        let time = (now as f32) / 1000. + offset - RENDER_DELAY;
+        let after = self.games.iter().position(|game| game.time >= time);
+        match after {
+            None    => self.games.back().map(|game| extrapolate_game(spec, &**game, time - game.time)),
+            Some(0) => self.games.front().map(|game| (**game).clone()),
+            Some(i) => {
+                let before = self.games.get(i - 1).unwrap();
+                let after = self.games.get(i).unwrap();
+                let alpha = (time - before.time) / (after.time - before.time);
+                Some(interpolate_game(&**before, &**after, alpha))
+            },
+        }
+    }
+}
+
+// ---------------------------------------------------------------------
+// Tests
+
+#[cfg(test)]
+fn game_at_time(time: f32) -> Arc<Game> {
+    let mut game = Game::empty();
+    game.time = time;
+    Arc::new(game)
+}
+
+#[test]
+fn test_game_buffer() {
+    let spec = ::test_spec();
+    let mut buffer = GameBuffer::new();
+    assert!(buffer.game_at(&spec, 0).is_none());
+
+    buffer.push(game_at_time(0.), 1000);
+    buffer.push(game_at_time(0.05), 1050);
+    // Arrived late
+    buffer.push(game_at_time(0.1), 1130);
+    // Older than the last one
+    buffer.push(game_at_time(0.05), 1140);
+    assert!(buffer.games.len() == 3);
+
+    let delay = (RENDER_DELAY * 1000.) as u32;
+    // Halfway between the last two
+    let game = buffer.game_at(&spec, 1075 + delay).unwrap();
+    assert!((game.time - 0.075).abs() < 0.001);
+    // Too early
+    assert!(buffer.game_at(&spec, 900 + delay).unwrap().time == 0.);
+    // Too late, so we go ahead on our own, but not too far
+    let late = buffer.game_at(&spec, 1125 + delay).unwrap();
+    assert!((late.time - 0.125).abs() < 0.001);
+    let very_late = buffer.game_at(&spec, 5000 + delay).unwrap();
+    assert!(very_late.time == 0.1 + MAX_EXTRAPOLATION);
+}
@@ -1,66 +1,117 @@
-// use geometry::*;
-// use actors::*;
-// use specs::*;
-// use input::*;
+// Moves a game forward assuming everybody keeps doing what they were
+// doing, for when the next game from the server is late.  Nothing
+// interacts and nothing new appears, so we only go so far.
 
-// #[inline]
-// fn extrapolate_pos(before: Vec2, vel: Vec2, dt: f32) -> Vec2 {
-//     before + vel * dt
-// }
+use geometry::*;
+use actors::*;
+use specs::*;
+use input::*;
+use conf::*;
 
-// #[inline]
-// fn extrapolate_rotation(before: f32, vel: f32, rotating: Rotating, dt: f32) -> f32 {
-//     match rotating {
-//         Rotating::Still => before,
-//         Rotating::Left => before + vel * dt,
-//         Rotating::Right => before - vel * dt,
-//     }
-// }
+#[inline]
+fn extrapolate_pos(before: Vec2, vel: Vec2, dt: f32) -> Vec2 {
+    before + vel * dt
+}
 
-// #[inline]
-// fn extrapolate_bullet(specs: &GameSpec, before: &Bullet, dt: f32) -> Bullet {
-//     let spec = specs.get_spec(before.spec).is_bullet();
-//     let vel = before.trans.pos.norm() * spec.vel;
-//     Bullet{
-//         spec: before.spec,
-//         trans: Transform{
-//             pos: extrapolate_pos(before.trans.pos, vel, dt),
-//             rotation: before.trans.rotation,
-//         },
-//         age: before.age + dt,
-//     }
-// }
+#[inline]
+fn extrapolate_rotation(before: f32, vel: f32, rotating: Rotating, dt: f32) -> f32 {
+    match rotating {
+        Rotating::Still => before,
+        Rotating::Left  => normalize_angle(before + vel * dt),
+        Rotating::Right => normalize_angle(before - vel * dt),
+    }
+}
 
-// #[inline]
-// fn extrapolate_camera(before: &Camera, dt: f32) -> Camera {
-//     Camera{
-//         pos: extrapolate_pos(before.pos, before.vel, dt),
-//         vel: before.vel,
-//     }
-// }
+#[inline]
+fn extrapolate_bullet(specs: &GameSpec, before: &Bullet, dt: f32) -> Option<Bullet> {
+    let spec = specs.get_spec(before.spec).is_bullet();
+    let age = before.age + dt;
+    if age >= spec.lifetime { return None };
+    Some(Bullet{
+        trans: Transform{
+            pos: extrapolate_pos(before.trans.pos, before.vel, dt),
+            rotation: before.trans.rotation,
+        },
+        age: age,
+        ..*before
+    })
+}
 
-// #[inline]
-// fn extrapolate_ship(specs: &GameSpec, before: &Ship, dt: f32) -> Ship {
-//     let spec = specs.get_spec(before.spec).is_ship();
-//     Ship{
-//         spec: before.spec,
-//         trans: Transform{
-//             pos: extrapolate_pos(before.trans.pos, before.vel, dt),
-//             rotation: extrapolate_rotation(before.trans.rotation, spec.rotation_vel, before.rotating, dt),
-//         },
-//         vel: before.vel,
-//         // TODO should we bump here?  and in interpolate?
-//         not_firing_for: before.not_firing_for,
-//         accel: before.accel,
-//         rotating: before.rotating,
-//         camera: extrapolate_camera(&before.camera, dt),
-//     }
-// }
+#[inline]
+fn extrapolate_camera(specs: &GameSpec, before: &Camera, dt: f32) -> Camera {
+    Camera{
+        pos: specs.map.bound_rect(extrapolate_pos(before.pos, before.vel, dt), SCREEN_WIDTH, SCREEN_HEIGHT),
+        vel: before.vel,
+    }
+}
 
-// #[inline]
-// fn extrapolate_shooter(before: &Shooter, _dt: f32) -> Shooter {
-//     *before
-// }
+#[inline]
+fn extrapolate_ship(specs: &GameSpec, before: &Ship, dt: f32) -> Ship {
+    // Dead ships stay where they are until the server respawns them
+    if !before.alive() { return *before };
+    let spec = specs.get_spec(before.spec).is_ship();
+    let rotation_vel = if before.accel { spec.rotation_vel_accel } else { spec.rotation_vel };
+    Ship{
+        trans: Transform{
+            pos: specs.map.bound(extrapolate_pos(before.trans.pos, before.vel, dt)),
+            rotation: extrapolate_rotation(before.trans.rotation, rotation_vel, before.rotating, dt),
+        },
+        camera: extrapolate_camera(specs, &before.camera, dt),
+        ..*before
+    }
+}
 
-// #[inline]
-// fn extrapolate_actor(before: &Actor, dt: f32) -> 
+#[inline]
+fn extrapolate_actor(specs: &GameSpec, before: &Actor, dt: f32) -> Option<Actor> {
+    match *before {
+        Actor::Ship(ref ship)       => Some(Actor::Ship(extrapolate_ship(specs, ship, dt))),
+        Actor::Shooter(ref shooter) => Some(Actor::Shooter(*shooter)),
+        Actor::Bullet(ref bullet)   => extrapolate_bullet(specs, bullet, dt).map(Actor::Bullet),
+    }
+}
+
+/// Never goes more than `MAX_EXTRAPOLATION` ahead of `game`, however
+/// big `dt` is.
+pub fn extrapolate_game(specs: &GameSpec, game: &Game, dt: f32) -> Game {
+    let dt = if dt > MAX_EXTRAPOLATION { MAX_EXTRAPOLATION } else { dt };
+    let mut actors = Actors::prepare_new(&game.actors);
+    for (actor_id, actor) in game.actors.iter() {
+        match extrapolate_actor(specs, actor, dt) {
+            None        => {},
+            Some(actor) => actors.insert(*actor_id, actor),
+        }
+    };
+    Game{
+        actors: actors,
+        scores: game.scores.clone(),
+        tick: game.tick,
+        time: game.time + dt,
+    }
+}
+
+// ---------------------------------------------------------------------
+// Tests
+
+#[test]
+fn test_extrapolate() {
+    let spec = ::test_spec();
+    let mut game = Game::empty();
+    let player = game.add_ship(&spec);
+    let ship = Ship{
+        vel: Vec2{x: 100., y: 0.},
+        rotating: Rotating::Left,
+        ..*game.actors.get(player).unwrap().is_ship()
+    };
+    game.actors.insert(player, Actor::Ship(ship));
+
+    let extrapolated = extrapolate_game(&spec, &game, 0.1);
+    let extrapolated_ship = *extrapolated.actors.get(player).unwrap().is_ship();
+    assert!(extrapolated_ship.trans.pos.x > ship.trans.pos.x);
+    assert!(extrapolated_ship.trans.rotation > ship.trans.rotation);
+
+    // Capped
+    let far = extrapolate_game(&spec, &game, 10.);
+    assert!(far.time == game.time + MAX_EXTRAPOLATION);
+    // Nothing actually happened
+    assert!(far.tick == game.tick);
+}
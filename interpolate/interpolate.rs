@@ -18,7 +18,7 @@ fn interpolate_vec2(before: Vec2, after: Vec2, alpha: f32) -> Vec2 {
 fn interpolate_trans(before: Transform, after: Transform, alpha: f32) -> Transform {
     Transform{
         pos: interpolate_vec2(before.pos, after.pos, alpha),
-        rotation: interpolate_f32(before.rotation, after.rotation, alpha),
+        rotation: interpolate_angle(before.rotation, after.rotation, alpha),
     }
 }
 
@@ -27,7 +27,9 @@ fn interpolate_bullet(before: &Bullet, after: &Bullet, alpha: f32) -> Bullet {
     assert!(before.spec == after.spec);
     Bullet{
         spec: before.spec,
+        owner: before.owner,
         trans: interpolate_trans(before.trans, after.trans, alpha),
+        vel: interpolate_vec2(before.vel, after.vel, alpha),
         age: interpolate_f32(before.age, after.age, alpha),
     }
 }
@@ -43,6 +45,10 @@ fn interpolate_camera(before: &Camera, after: &Camera, alpha: f32) -> Camera {
 #[inline]
 fn interpolate_ship(before: &Ship, after: &Ship, alpha: f32) -> Ship {
     assert!(before.spec == after.spec);
+    // Ships teleport when they die or respawn, don't slide them
+    if !before.alive() || !after.alive() {
+        return *after;
+    }
     Ship{
         spec: before.spec,
         trans: interpolate_trans(before.trans, after.trans, alpha),
@@ -50,15 +56,21 @@ fn interpolate_ship(before: &Ship, after: &Ship, alpha: f32) -> Ship {
         camera: interpolate_camera(&before.camera, &after.camera, alpha),
         // TODO should we bump here?  and in extrapolate?        
         not_firing_for: before.not_firing_for,
+        health: before.health,
+        dead_for: before.dead_for,
+        killed_by: before.killed_by,
         accel: before.accel,
         rotating: before.rotating,
     }
 }
 
 #[inline]
-fn interpolate_shooter(before: &Shooter, after: &Shooter, _alpha: f32) -> Shooter {
+fn interpolate_shooter(before: &Shooter, after: &Shooter, alpha: f32) -> Shooter {
     assert!(before.spec == after.spec);
-    *before
+    Shooter{
+        trans: interpolate_trans(before.trans, after.trans, alpha),
+        ..*before
+    }
 }
 
 #[inline]
@@ -93,6 +105,9 @@ fn interpolate_actors(before: &Actors, after: &Actors, alpha: f32) -> Actors {
 pub fn interpolate_game(before: &Game, after: &Game, alpha: f32) -> Game {
     Game{
         actors: interpolate_actors(&before.actors, &after.actors, alpha),
+        scores: after.scores.clone(),
+        // The last tick which actually happened
+        tick: before.tick,
         time: interpolate_f32(before.time, after.time, alpha),
     }
 }
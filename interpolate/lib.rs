@@ -1,10 +1,28 @@
+#![allow(unstable)]
 extern crate geometry;
 extern crate actors;
 extern crate specs;
 extern crate input;
+extern crate conf;
+
+#[cfg(test)] use std::collections::HashMap;
+#[cfg(test)] use specs::GameSpec;
 
 pub use interpolate::*;
 pub use extrapolate::*;
+pub use buffer::GameBuffer;
 
 mod interpolate;
 mod extrapolate;
+mod buffer;
+
+// ---------------------------------------------------------------------
+// Tests
+
+#[cfg(test)]
+fn test_spec() -> GameSpec {
+    let mut textures = HashMap::new();
+    let _ = textures.insert("planes".to_string(), 0);
+    let _ = textures.insert("background".to_string(), 1);
+    GameSpec::from_json(include_str!("../assets/spec.json"), &textures).ok().unwrap()
+}
@@ -2,45 +2,124 @@
 #![allow(unstable)]
 #![warn(unused_results)]
 extern crate "rustc-serialize" as rustc_serialize;
-extern crate sdl2;
 extern crate bincode;
 #[macro_use] extern crate log;
 
 extern crate conf;
+extern crate clock;
 
-use std::io::net::udp::UdpSocket;
 use std::io::net::ip::{SocketAddr, ToSocketAddr};
-use std::collections::HashMap;
+use std::collections::{HashMap, RingBuf};
 use std::collections::hash_map::Entry;
+use std::cmp::min;
 use std::io::{IoError, IoResult, IoErrorKind, BufWriter, BufReader};
 use std::sync::{Arc, Mutex};
 use std::ops::DerefMut;
 use std::thread::{Thread};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::ptr;
-use rustc_serialize::{Encodable, Decodable};
+use std::mem;
+use std::rand;
+use std::u32;
+use std::num::Float;
+use rustc_serialize::{Encodable, Decodable, Encoder};
 
 use conf::*;
+use clock::Clock;
+
+pub use link::{Conditions, Link};
+use link::Socket;
+
+mod link;
 
 // ---------------------------------------------------------------------
 // Packet
 
-#[derive(PartialEq, Clone, Copy, Show, RustcDecodable, RustcEncodable)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Show, RustcDecodable, RustcEncodable)]
 pub struct Seq(u32);
 
 impl Seq {
     #[inline]
     fn bump(&mut self) {
-        self.0 += 1;
+        self.0 = self.0.checked_add(1).unwrap_or(0);
     }
 }
 
 impl Seq {
-    // Returns if it's more recent and the difference between the two.
-    // FIXME: actually wrap around
+    // How many seqs after `other` this is, wrapping around.
+    #[inline]
+    fn distance(self, other: Seq) -> u32 {
+        if self.0 >= other.0 {
+            self.0 - other.0
+        } else {
+            (u32::MAX - other.0) + self.0 + 1
+        }
+    }
+
+    // Seqs are compared assuming that they are less than half the range
+    // apart, so that right after the wrap `Seq(0)` is newer than
+    // `Seq(u32::MAX)`.
     #[inline]
-    fn more_recent(x: Seq, y: Seq) -> Seq {
-        if x.0 > y.0 { x } else { y }
+    fn newer_than(self, other: Seq) -> bool {
+        self != other && self.distance(other) <= u32::MAX / 2
+    }
+}
+
+const ACK_BITS: u32 = 32;
+
+#[inline]
+fn shift_left(bits: u32, n: u32) -> u32 {
+    if n >= ACK_BITS { 0 } else { bits << n as usize }
+}
+
+/// The most recent seq received, and which of the `ACK_BITS` seqs
+/// before it were received too: bit `n` is set if `last - (n + 1)` was.
+#[derive(PartialEq, Clone, Copy, Show, RustcDecodable, RustcEncodable)]
+struct Acks {
+    last: Seq,
+    previous: u32,
+}
+
+impl Acks {
+    fn new() -> Acks {
+        Acks{last: Seq(0), previous: 0}
+    }
+
+    fn insert(&mut self, seq: Seq) {
+        if seq.newer_than(self.last) {
+            let diff = seq.distance(self.last);
+            self.previous = shift_left(self.previous, diff) | shift_left(1, diff - 1);
+            self.last = seq;
+        } else if seq != self.last {
+            let diff = self.last.distance(seq);
+            if diff <= ACK_BITS {
+                self.previous = self.previous | shift_left(1, diff - 1);
+            }
+        }
+    }
+
+    fn contains(&self, seq: Seq) -> bool {
+        if seq == self.last {
+            true
+        } else if self.last.newer_than(seq) {
+            let diff = self.last.distance(seq);
+            diff <= ACK_BITS && self.previous & shift_left(1, diff - 1) != 0
+        } else {
+            false
+        }
+    }
+
+    // Headers can arrive out of order, so we add what the remote tells
+    // us to what we know already instead of replacing it.
+    fn merge(&mut self, other: &Acks) {
+        self.insert(other.last);
+        let mut seq = other.last;
+        for n in range(0, ACK_BITS) {
+            seq = Seq(seq.0.checked_sub(1).unwrap_or(u32::MAX));
+            if other.previous & shift_left(1, n) != 0 {
+                self.insert(seq);
+            }
+        }
     }
 }
 
@@ -48,23 +127,76 @@ impl Seq {
 struct Local {
     /// Our local seq number
     seq: Seq,
-    /// The last remote message we have acked
-    ack: Seq,
+    /// The remote messages we have received
+    ack: Acks,
+    /// The seq of the next reliable message we expect, we got all the
+    /// ones before it
+    reliable_next: Seq,
 }
 
 #[derive(PartialEq, Clone, Copy, Show, RustcDecodable, RustcEncodable)]
 struct Remote {
-    /// Last message the remote ack'ed
-    ack: Seq,
+    /// Our messages the remote has received
+    ack: Acks,
+    /// The reliable messages the remote has received, as in `Local`
+    reliable_next: Seq,
     /// The last time we received a message from remote
     received: u32,
 }
 
+/// Why a server turned down a connection.
+#[derive(PartialEq, Clone, Copy, Show, RustcDecodable, RustcEncodable)]
+pub enum RejectReason {
+    ServerFull,
+    ProtocolMismatch,
+}
+
+// The handshake goes
+//
+//   client                      server
+//   ConnectRequest(salt)  ->
+//                         <-    Challenge(salt, server_salt)
+//   ChallengeResponse(salt ^ server_salt)  ->
+//                         <-    Accept | Reject(reason)
+//
+// so that the server only creates connections for clients that can
+// receive at the address they claim.  The client keeps resending until
+// it gets an answer, so the server must be happy to answer twice.
 #[derive(PartialEq, Clone, Copy, Show, RustcDecodable, RustcEncodable)]
 enum MsgType {
+    ConnectRequest(u64),
+    Challenge(u64, u64),
+    ChallengeResponse(u64),
+    Accept,
+    Reject(RejectReason),
+    Disconnect,
     Ping,
     Pong,
     Normal,
+    /// With the seq of the message among the reliable ones
+    Reliable(Seq),
+    Fragment(Fragment),
+}
+
+/// A piece of a body too big for one packet.
+#[derive(PartialEq, Clone, Copy, Show, RustcDecodable, RustcEncodable)]
+struct Fragment {
+    /// All the fragments of a body share this
+    id: Seq,
+    index: u16,
+    count: u16,
+    /// The seq of the body among the reliable ones, if it is one
+    reliable: Option<Seq>,
+}
+
+/// How a message should be delivered.
+#[derive(PartialEq, Clone, Copy, Show)]
+pub enum Channel {
+    /// Might be lost, duplicated, or arrive out of order
+    Unreliable,
+    /// Arrives exactly once, and in order with the other reliable
+    /// messages
+    Reliable,
 }
 
 #[derive(PartialEq, Clone, Copy, Show, RustcDecodable, RustcEncodable)]
@@ -84,56 +216,302 @@ impl Header {
     }
 }
 
+// ---------------------------------------------------------------------
+// Stats
+
+/// How a connection is doing.  Times are in milliseconds.
+#[derive(PartialEq, Clone, Copy, Show)]
+pub struct Stats {
+    /// Smoothed round trip time
+    pub rtt: f32,
+    /// How much the round trip time strays from `rtt`, smoothed too
+    pub jitter: f32,
+    /// The fraction of our packets which got lost lately, from 0 to 1
+    pub loss: f32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+// We measure the round trip with every packet that gets acked, and
+// count as lost the ones which fall out of the acks window without
+// being acked.
+#[derive(Clone)]
+struct Measure {
+    /// Our packets which were not acked yet, with when we sent them,
+    /// oldest first
+    in_flight: RingBuf<(Seq, u32)>,
+    measured_rtt: bool,
+    stats: Stats,
+}
+
+impl Measure {
+    fn new() -> Measure {
+        Measure{
+            in_flight: RingBuf::new(),
+            measured_rtt: false,
+            stats: Stats{rtt: 0., jitter: 0., loss: 0., bytes_sent: 0, bytes_received: 0},
+        }
+    }
+
+    fn sent(&mut self, seq: Seq, bytes: usize, now: u32) {
+        self.stats.bytes_sent += bytes as u64;
+        self.in_flight.push_back((seq, now));
+        // If the remote stops talking to us we'll time out anyway
+        if self.in_flight.len() > MAX_IN_FLIGHT {
+            let _ = self.in_flight.pop_front();
+        }
+    }
+
+    fn received(&mut self, bytes: usize) {
+        self.stats.bytes_received += bytes as u64;
+    }
+
+    fn acked(&mut self, acks: &Acks, now: u32) {
+        let in_flight = mem::replace(&mut self.in_flight, RingBuf::new());
+        for &(seq, sent) in in_flight.iter() {
+            if acks.contains(seq) {
+                self.rtt_sample((now - sent) as f32);
+                self.loss_sample(0.);
+            } else if acks.last.newer_than(seq) && acks.last.distance(seq) > ACK_BITS {
+                self.loss_sample(1.);
+            } else {
+                self.in_flight.push_back((seq, sent));
+            }
+        }
+    }
+
+    fn rtt_sample(&mut self, rtt: f32) {
+        if !self.measured_rtt {
+            self.stats.rtt = rtt;
+            self.measured_rtt = true;
+        }
+        let deviation = (rtt - self.stats.rtt).abs();
+        self.stats.jitter += (deviation - self.stats.jitter) * STATS_SMOOTHING;
+        self.stats.rtt += (rtt - self.stats.rtt) * STATS_SMOOTHING;
+    }
+
+    fn loss_sample(&mut self, lost: f32) {
+        self.stats.loss += (lost - self.stats.loss) * STATS_SMOOTHING;
+    }
+}
+
 // ---------------------------------------------------------------------
 // Lightweight connection
 
-#[derive(Copy, Clone)]
+// A reliable message waiting to be acked.
+#[derive(Clone)]
+struct Unacked {
+    seq: Seq,
+    body: Vec<u8>,
+    /// The packets it was last sent in which weren't acked yet, and
+    /// when; `None` if it hasn't been sent yet
+    sent: Option<(Vec<Seq>, u32)>,
+}
+
+// A fragmented body we're still receiving.
+#[derive(Clone)]
+struct Partial {
+    reliable: Option<Seq>,
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    started: u32,
+}
+
+#[derive(Clone)]
 struct Conn {
     local: Local,
     remote: Remote,
+    /// The remote told us it's gone
+    closed: bool,
+    /// The seq of the next reliable message we send
+    reliable_seq: Seq,
+    unacked: RingBuf<Unacked>,
+    /// Reliable messages waiting for the ones before them
+    reliable_early: HashMap<Seq, Vec<u8>>,
+    /// Bodies received and not handed out yet, in order
+    ready: RingBuf<Vec<u8>>,
+    /// The id of the next body we fragment
+    fragment_id: Seq,
+    /// Fragmented bodies with some pieces missing
+    partials: HashMap<Seq, Partial>,
+    measure: Measure,
+    clock: Clock,
 }
 
 impl Conn {
-    fn new() -> Conn {
+    fn new(clock: Clock) -> Conn {
         Conn{
             local: Local{
                 seq: Seq(0),
-                ack: Seq(0),
+                ack: Acks::new(),
+                reliable_next: Seq(0),
             },
             remote: Remote{
-                ack: Seq(0),
-                received: sdl2::get_ticks(),
-            }
+                ack: Acks::new(),
+                reliable_next: Seq(0),
+                received: clock.ticks(),
+            },
+            closed: false,
+            reliable_seq: Seq(0),
+            unacked: RingBuf::new(),
+            reliable_early: HashMap::new(),
+            ready: RingBuf::new(),
+            fragment_id: Seq(0),
+            partials: HashMap::new(),
+            measure: Measure::new(),
+            clock: clock,
         }
     }
 
     fn tickle(&mut self, remote_local: &Local) {
-        self.local.ack = Seq::more_recent(self.local.ack, remote_local.seq);
-        self.remote.received = sdl2::get_ticks();
-        self.remote.ack = Seq::more_recent(self.remote.ack, remote_local.ack);
+        let now = self.clock.ticks();
+        self.local.ack.insert(remote_local.seq);
+        self.remote.received = now;
+        self.remote.ack.merge(&remote_local.ack);
+        if remote_local.reliable_next.newer_than(self.remote.reliable_next) {
+            self.remote.reliable_next = remote_local.reliable_next;
+        }
+        self.measure.acked(&self.remote.ack, now);
+        self.forget_acked();
+    }
+
+    // Reliable messages are done with once the remote got all the ones
+    // up to them, or all the packets we last sent them in.  The acks
+    // only go `ACK_BITS` packets back, so we mark the packets off as
+    // each header arrives rather than checking them when resending.
+    fn forget_acked(&mut self) {
+        let (acks, reliable_next) = (self.remote.ack, self.remote.reliable_next);
+        let unacked = mem::replace(&mut self.unacked, RingBuf::new());
+        self.unacked = unacked.into_iter().filter_map(|mut msg| {
+            if reliable_next.newer_than(msg.seq) { return None };
+            let acked = match msg.sent {
+                None => false,
+                Some((ref mut packets, _)) => {
+                    packets.retain(|packet| !acks.contains(*packet));
+                    packets.is_empty()
+                },
+            };
+            if acked { None } else { Some(msg) }
+        }).collect();
+    }
+
+    // Duplicates are dropped, and the ones which arrive early are kept
+    // until the gap is filled.
+    fn receive_reliable(&mut self, seq: Seq, body: &[u8]) {
+        if seq != self.local.reliable_next && !seq.newer_than(self.local.reliable_next) {
+            debug!("Got reliable message {:?} again, dropping", seq);
+            return;
+        }
+        if seq.distance(self.local.reliable_next) >= MAX_RELIABLE_EARLY {
+            warn!("Reliable message {:?} too far ahead of {:?}, dropping", seq, self.local.reliable_next);
+            return;
+        }
+        let _ = self.reliable_early.insert(seq, body.to_vec());
+        loop {
+            match self.reliable_early.remove(&self.local.reliable_next) {
+                None => break,
+                Some(body) => {
+                    self.ready.push_back(body);
+                    self.local.reliable_next.bump();
+                }
+            }
+        }
+    }
+
+    // Bodies which are still missing pieces after `FRAGMENT_TIMEOUT` are
+    // dropped -- reliable ones will be sent again.
+    fn receive_fragment(&mut self, fragment: Fragment, body: &[u8]) {
+        let now = self.clock.ticks();
+        let stale: Vec<Seq> = self.partials.iter()
+            .filter(|&(_, partial)| now - partial.started > FRAGMENT_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in stale.iter() {
+            debug!("Fragmented body {:?} timed out, dropping", id);
+            let _ = self.partials.remove(id);
+        }
+
+        let (index, count) = (fragment.index as usize, fragment.count as usize);
+        if index >= count || count > MAX_FRAGMENTS {
+            warn!("Bad fragment {:?}, dropping", fragment);
+            return;
+        }
+        if !self.partials.contains_key(&fragment.id) && self.partials.len() >= MAX_PARTIALS {
+            // Make room by giving up on the oldest one
+            let oldest = self.partials.iter()
+                .fold(None, |oldest: Option<(Seq, u32)>, (id, partial)| match oldest {
+                    Some((oldest_id, started)) if started < partial.started ||
+                        (started == partial.started && id.newer_than(oldest_id)) => oldest,
+                    _ => Some((*id, partial.started)),
+                })
+                .unwrap().0;
+            debug!("Too many fragmented bodies, dropping {:?}", oldest);
+            let _ = self.partials.remove(&oldest);
+        }
+        let complete = {
+            let partial = match self.partials.entry(fragment.id) {
+                Entry::Vacant(entry) => entry.insert(Partial{
+                    reliable: fragment.reliable,
+                    fragments: range(0, count).map(|_| None).collect(),
+                    missing: count,
+                    started: now,
+                }),
+                Entry::Occupied(entry) => entry.into_mut(),
+            };
+            if partial.fragments.len() != count {
+                warn!("Fragment {:?} does not match the others, dropping", fragment);
+                return;
+            }
+            if partial.fragments[index].is_none() {
+                partial.fragments[index] = Some(body.to_vec());
+                partial.missing -= 1;
+            }
+            partial.missing == 0
+        };
+        if complete {
+            let partial = self.partials.remove(&fragment.id).unwrap();
+            let mut body = Vec::new();
+            for piece in partial.fragments.into_iter() {
+                body.push_all(piece.unwrap().as_slice());
+            }
+            match partial.reliable {
+                None      => self.ready.push_back(body),
+                Some(seq) => self.receive_reliable(seq, body.as_slice()),
+            }
+        }
     }
 }
 
-fn encode_and_send<T: Encodable>(conn: &mut Conn, sock: &mut UdpSocket, buf: &mut [u8], addr: SocketAddr, msg_type: MsgType, body: &T) -> IoResult<()> {
-    let now = sdl2::get_ticks();
-    if now - conn.remote.received > CONN_TIMEOUT {
-        debug!("Connection {} timed out", addr);
-        return Err(IoError{
-            kind: IoErrorKind::Closed,
-            desc: "network::encode_and_send: Connection timed out",
-            detail: None,
-        });
+// Bodies we encoded already, written out as they are.
+struct Raw<'a>(&'a [u8]);
+
+impl<'a> Encodable for Raw<'a> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        for b in self.0.iter() {
+            try!(s.emit_u8(*b));
+        }
+        Ok(())
     }
+}
 
+fn closed_error(desc: &'static str) -> IoError {
+    IoError{
+        kind: IoErrorKind::Closed,
+        desc: desc,
+        detail: None,
+    }
+}
+
+// Returns the length of the packet.
+fn send_packet<T: Encodable>(sock: &mut Socket, buf: &mut [u8], addr: SocketAddr, header: Header, body: &T) -> IoResult<usize> {
     #[derive(RustcEncodable)]
     struct Packet<'a, T: 'a> {
         header: Header,
         body: &'a T,
     }
 
-    conn.local.seq.bump();
     let packet = Packet{
-        header: Header::new(conn.local, msg_type),
+        header: header,
         body: body
     };
     let len = {
@@ -143,66 +521,208 @@ fn encode_and_send<T: Encodable>(conn: &mut Conn, sock: &mut UdpSocket, buf: &mu
     };
     try!(sock.send_to(buf.slice_to(len), addr));
     debug!("Message sent to {}", addr);
+    Ok(len)
+}
+
+fn encode_and_send<T: Encodable>(conn: &mut Conn, sock: &mut Socket, buf: &mut [u8], addr: SocketAddr, msg_type: MsgType, body: &T) -> IoResult<()> {
+    if conn.closed {
+        return Err(closed_error("network::encode_and_send: Connection closed by remote"));
+    }
+    let now = conn.clock.ticks();
+    if now - conn.remote.received > CONN_TIMEOUT {
+        debug!("Connection {} timed out", addr);
+        return Err(closed_error("network::encode_and_send: Connection timed out"));
+    }
+
+    conn.local.seq.bump();
+    let len = try!(send_packet(sock, buf, addr, Header::new(conn.local, msg_type), body));
+    conn.measure.sent(conn.local.seq, len, now);
+    Ok(())
+}
+
+// Sends the reliable messages which were never sent or were not acked
+// in time, see `Conn::forget_acked`.  We don't know if the ones that
+// weren't acked got lost or the ack did, so the remote might get them
+// more than once.
+fn resend(conn: &mut Conn, sock: &mut Socket, addr: SocketAddr) -> IoResult<()> {
+    if conn.unacked.is_empty() { return Ok(()) };
+    let now = conn.clock.ticks();
+    let mut unacked = mem::replace(&mut conn.unacked, RingBuf::new());
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let mut res = Ok(());
+    for msg in unacked.iter_mut() {
+        let due = match msg.sent {
+            None              => true,
+            Some((_, ref at)) => now - *at > RESEND_INTERVAL,
+        };
+        if due && res.is_ok() {
+            match send_bytes(conn, sock, &mut buf, addr, Some(msg.seq), msg.body.as_slice()) {
+                Ok(packets) => msg.sent = Some((packets, now)),
+                Err(err)    => res = Err(err),
+            }
+        }
+    }
+    conn.unacked = unacked;
+    res
+}
+
+// Sends an encoded body, in fragments if it doesn't fit in a packet.
+// Returns the packets it went in.
+fn send_bytes(conn: &mut Conn, sock: &mut Socket, buf: &mut [u8], addr: SocketAddr, reliable: Option<Seq>, body: &[u8]) -> IoResult<Vec<Seq>> {
+    if body.len() <= FRAGMENT_SIZE {
+        let msg_type = match reliable {
+            None      => MsgType::Normal,
+            Some(seq) => MsgType::Reliable(seq),
+        };
+        try!(encode_and_send(conn, sock, buf, addr, msg_type, &Raw(body)));
+        return Ok(vec![conn.local.seq]);
+    }
+
+    let count = (body.len() + FRAGMENT_SIZE - 1) / FRAGMENT_SIZE;
+    if count > MAX_FRAGMENTS {
+        return Err(IoError{
+            kind: IoErrorKind::InvalidInput,
+            desc: "network::send_bytes: Body too big",
+            detail: Some(format!("{} bytes", body.len())),
+        });
+    }
+    let id = conn.fragment_id;
+    conn.fragment_id.bump();
+    let mut packets = Vec::with_capacity(count);
+    for (index, piece) in body.chunks(FRAGMENT_SIZE).enumerate() {
+        let fragment = Fragment{
+            id: id,
+            index: index as u16,
+            count: count as u16,
+            reliable: reliable,
+        };
+        try!(encode_and_send(conn, sock, buf, addr, MsgType::Fragment(fragment), &Raw(piece)));
+        packets.push(conn.local.seq);
+    }
+    Ok(packets)
+}
+
+fn send_on<T: Encodable>(conn: &mut Conn, sock: &mut Socket, buf: &mut [u8], addr: SocketAddr, chan: Channel, body: &T) -> IoResult<()> {
+    match chan {
+        Channel::Unreliable => {
+            let body = try!(bincode::encode(body));
+            let _ = try!(send_bytes(conn, sock, buf, addr, None, body.as_slice()));
+        },
+        Channel::Reliable => {
+            let body = try!(bincode::encode(body));
+            conn.unacked.push_back(Unacked{seq: conn.reliable_seq, body: body, sent: None});
+            conn.reliable_seq.bump();
+        },
+    };
+    resend(conn, sock, addr)
+}
+
+// Handshake messages are sent before there is a connection.  The
+// proto-id is a parameter since when rejecting a client for having the
+// wrong one we need to use theirs, or they'd drop the answer.
+fn send_control(sock: &mut Socket, addr: SocketAddr, proto_id: u32, msg_type: MsgType) -> IoResult<()> {
+    let mut buf: [u8; 200] = [0; 200];
+    let header = Header{
+        proto_id: proto_id,
+        local: Local{seq: Seq(0), ack: Acks::new(), reliable_next: Seq(0)},
+        msg_type: msg_type,
+    };
+    let _ = try!(send_packet(sock, &mut buf, addr, header, &()));
     Ok(())
 }
 
-fn send_ping(conn: &mut Conn, sock: &mut UdpSocket, addr: SocketAddr) -> IoResult<()> {
+fn send_ping(conn: &mut Conn, sock: &mut Socket, addr: SocketAddr) -> IoResult<()> {
     let mut buf: [u8; 200] = [0; 200];
     encode_and_send(conn, sock, &mut buf, addr, MsgType::Ping, &())
 }
 
-fn send_pong(conn: &mut Conn, sock: &mut UdpSocket, addr: SocketAddr) -> IoResult<()> {
+fn send_pong(conn: &mut Conn, sock: &mut Socket, addr: SocketAddr) -> IoResult<()> {
     let mut buf: [u8; 200] = [0; 200];
     encode_and_send(conn, sock, &mut buf, addr, MsgType::Pong, &())
 }
 
-fn recv_and_decode_1(sock: &mut UdpSocket, buf: &mut [u8]) -> IoResult<SocketAddr> {
+// The disconnection is not acknowledged, so we say it a few times and
+// hope that one gets through.  If none does the remote will time out
+// anyway.
+fn send_disconnect(conn: &mut Conn, sock: &mut Socket, addr: SocketAddr) -> IoResult<()> {
+    let mut buf: [u8; 200] = [0; 200];
+    for _ in range(0, DISCONNECT_REPEATS) {
+        try!(encode_and_send(conn, sock, &mut buf, addr, MsgType::Disconnect, &()));
+    }
+    Ok(())
+}
+
+// Returns the length of the packet too.
+fn recv_and_decode_1(sock: &mut Socket, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
     debug!("Blocking to receive");
-    let (_, addr) = try!(sock.recv_from(buf));
+    let (len, addr) = try!(sock.recv_from(buf));
     debug!("Received message from {}", addr);
-    Ok(addr)
+    Ok((len, addr))
 }
 
-fn recv_and_decode_2<T: Decodable>(conn: &mut Conn, addr: SocketAddr, sock: &mut UdpSocket, buf: &mut [u8]) -> IoResult<Option<T>> {
-    #[derive(RustcDecodable)]
-    struct Packet<T> {
-        header: Header,
-        body: T,
+// Returns the header and the body that follows.
+fn decode_header(packet: &[u8]) -> Option<(Header, &[u8])> {
+    let mut r = BufReader::new(packet);
+    let header: bincode::DecodingResult<Header> = bincode::decode_from(&mut r);
+    match header {
+        Err(err) => {
+            warn!("Error while decoding header: {}, dropping", err);
+            None
+        },
+        Ok(header) => match r.tell() {
+            Err(err) => {
+                warn!("Error while decoding header: {}, dropping", err);
+                None
+            },
+            Ok(pos) => Some((header, packet.slice_from(pos as usize))),
+        },
     }
+}
 
-    let mut r = BufReader::new(buf);
-    let packet: bincode::DecodingResult<Packet<T>> = bincode::decode_from(&mut r);
-    match packet {
+fn decode_body<T: Decodable>(body: &[u8]) -> Option<T> {
+    let mut r = BufReader::new(body);
+    let body: bincode::DecodingResult<T> = bincode::decode_from(&mut r);
+    match body {
         Err(err) => {
             warn!("Error while decoding: {}, dropping", err);
-            Ok(None)
+            None
         },
-        Ok(packet) => {
-            let proto_id = packet.header.proto_id;
-            if proto_id != PROTO_ID {
-                warn!("Mismatching proto-id, got {}, expecting {}", packet.header.proto_id, PROTO_ID);
-                Ok(None)
-            } else {
-                conn.tickle(&packet.header.local);
-                match packet.header.msg_type {
-                    MsgType::Ping => {
-                        try!(send_pong(conn, sock, addr));
-                        Ok(None)
-                    },
-                    MsgType::Pong => Ok(None),
-                    MsgType::Normal => Ok(Some(packet.body))
-                }
-            }
-        }
+        Ok(body) => Some(body),
     }
 }
 
+// Bodies for the user end up in `conn.ready`.  Returns a `Closed` error
+// if the remote disconnected.  `len` is the length of the whole packet.
+fn recv_and_decode_2(conn: &mut Conn, addr: SocketAddr, sock: &mut Socket, len: usize, header: Header, body: &[u8]) -> IoResult<()> {
+    if header.proto_id != PROTO_ID {
+        warn!("Mismatching proto-id, got {}, expecting {}", header.proto_id, PROTO_ID);
+        return Ok(());
+    }
+    conn.measure.received(len);
+    conn.tickle(&header.local);
+    match header.msg_type {
+        MsgType::Ping => try!(send_pong(conn, sock, addr)),
+        MsgType::Pong => (),
+        MsgType::Disconnect => {
+            debug!("Connection {} closed by remote", addr);
+            conn.closed = true;
+            return Err(closed_error("network::recv_and_decode_2: Connection closed by remote"));
+        },
+        MsgType::Normal => conn.ready.push_back(body.to_vec()),
+        MsgType::Reliable(seq) => conn.receive_reliable(seq, body),
+        MsgType::Fragment(fragment) => conn.receive_fragment(fragment, body),
+        // Leftovers from the handshake, e.g. a duplicate `Accept`
+        msg_type => debug!("Unexpected {:?} from {}, dropping", msg_type, addr),
+    };
+    Ok(())
+}
+
 // ---------------------------------------------------------------------
 // Client
 
 pub struct ClientHandle {
     connected_to: SocketAddr,
-    socket: UdpSocket,
+    socket: Socket,
     conn: Arc<Mutex<Conn>>,
     buf: [u8; MAX_PACKET_SIZE],
 }
@@ -226,13 +746,30 @@ pub struct Client {
 }
 
 impl Client {
+    /// Blocks until the server accepts us.  If it refuses, we get a
+    /// `ConnectionRefused` error with the `RejectReason` in the
+    /// detail.
     pub fn new<A: ToSocketAddr, B: ToSocketAddr>(connect_to: A, listen_on: B, ping: bool) -> IoResult<Client> {
+        Client::conditioned(connect_to, listen_on, ping, Link::perfect())
+    }
+
+    /// Like `new`, but everything we send and receive goes through
+    /// `link`, the handshake included.
+    pub fn conditioned<A: ToSocketAddr, B: ToSocketAddr>(connect_to: A, listen_on: B, ping: bool, link: Link) -> IoResult<Client> {
+        Client::with_clock(connect_to, listen_on, ping, link, Clock::real())
+    }
+
+    /// Like `conditioned`, with the time coming from `clock`.  Socket
+    /// timeouts are still in real time.
+    pub fn with_clock<A: ToSocketAddr, B: ToSocketAddr>(connect_to: A, listen_on: B, ping: bool, link: Link, clock: Clock) -> IoResult<Client> {
         let connected_to = try!(connect_to.to_socket_addr());
-        let sock = try!(UdpSocket::bind(listen_on));
-        let conn = Arc::new(Mutex::new(Conn::new()));
+        let mut sock = try!(Socket::bind(listen_on, link, clock.clone()));
+        try!(Client::connect(&mut sock, connected_to, &clock));
+        sock.set_timeout(None);
+        let conn = Arc::new(Mutex::new(Conn::new(clock.clone())));
         let tx = if ping {
             let (tx, rx) = channel();
-            Client::ping_worker(&sock, &conn, connected_to, rx);
+            Client::ping_worker(&sock, &conn, connected_to, rx, clock);
             Some(tx)
         } else {
             None
@@ -248,11 +785,72 @@ impl Client {
         })
     }
 
+    fn connect(sock: &mut Socket, addr: SocketAddr, clock: &Clock) -> IoResult<()> {
+        let salt: u64 = rand::random();
+        let mut response: Option<u64> = None;
+        let mut buf: [u8; 200] = [0; 200];
+        let started = clock.ticks();
+        sock.set_timeout(Some(CONNECT_RETRY as u64));
+        loop {
+            if clock.ticks() - started > CONNECT_TIMEOUT {
+                return Err(IoError{
+                    kind: IoErrorKind::TimedOut,
+                    desc: "network::Client::new: Server did not answer",
+                    detail: None,
+                });
+            }
+            let msg_type = match response {
+                None           => MsgType::ConnectRequest(salt),
+                Some(response) => MsgType::ChallengeResponse(response),
+            };
+            try!(send_control(sock, addr, PROTO_ID, msg_type));
+
+            // Wait for an answer until it's time to ask again
+            loop {
+                let (len, from) = match recv_and_decode_1(sock, &mut buf) {
+                    Err(ref err) if err.kind == IoErrorKind::TimedOut => break,
+                    Err(err) => return Err(err),
+                    Ok(received) => received,
+                };
+                if from != addr {
+                    warn!("Got message from unknown sender {}, expected {}", from, addr);
+                    continue;
+                }
+                let header = match decode_header(buf.slice_to(len)) {
+                    None              => continue,
+                    Some((header, _)) => header,
+                };
+                if header.proto_id != PROTO_ID {
+                    warn!("Mismatching proto-id, got {}, expecting {}", header.proto_id, PROTO_ID);
+                    continue;
+                }
+                match header.msg_type {
+                    MsgType::Challenge(their_salt, server_salt) if their_salt == salt => {
+                        response = Some(salt ^ server_salt);
+                        break;
+                    },
+                    MsgType::Accept if response.is_some() => {
+                        info!("Connected to {}", addr);
+                        return Ok(());
+                    },
+                    MsgType::Reject(reason) => {
+                        return Err(IoError{
+                            kind: IoErrorKind::ConnectionRefused,
+                            desc: "network::Client::new: Server rejected the connection",
+                            detail: Some(format!("{:?}", reason)),
+                        });
+                    },
+                    msg_type => debug!("Unexpected {:?} while connecting, dropping", msg_type),
+                }
+            }
+        }
+    }
+
     pub fn handle(&self) -> ClientHandle {
         self.handle.clone()
     }
 
-    fn ping_worker(sock: &UdpSocket, conn: &Arc<Mutex<Conn>>, addr: SocketAddr, close_signal: Receiver<()>) {
+    fn ping_worker(sock: &Socket, conn: &Arc<Mutex<Conn>>, addr: SocketAddr, close_signal: Receiver<()>, clock: Clock) {
         let mut sock = sock.clone();
         let conn: Arc<Mutex<Conn>> = conn.clone();
         let _ = Thread::spawn(move || {
@@ -266,32 +864,49 @@ impl Client {
                 // until the delay is done!
                 {
                     let mut conn = conn.lock().unwrap();
-                    match send_ping(conn.deref_mut(), &mut sock, addr) {
+                    let res = match send_ping(conn.deref_mut(), &mut sock, addr) {
+                        // In case we're not sending anything else
+                        Ok(()) => resend(conn.deref_mut(), &mut sock, addr),
+                        Err(err) => Err(err),
+                    };
+                    match res {
                         Ok(()) => (),
                         Err(err) => warn!("network::Client::ping_worker: got error {}", err),
                     };
                 }
-                sdl2::timer::delay(PING_INTERVAL as usize);
+                clock.delay(PING_INTERVAL);
             }
         });
     }
 }
 
 impl ClientHandle {
-    pub fn send<T: Encodable>(&mut self, body: &T) -> IoResult<()> {
-        // TODO handle disconnections
+    pub fn send<T: Encodable>(&mut self, body: &T, chan: Channel) -> IoResult<()> {
         let mut conn = self.conn.lock().unwrap();
-        encode_and_send(conn.deref_mut(), &mut self.socket, &mut self.buf, self.connected_to, MsgType::Normal, &body)
+        send_on(conn.deref_mut(), &mut self.socket, &mut self.buf, self.connected_to, chan, body)
     }
 
+    /// Fails with `Closed` once the server has disconnected us.
     pub fn recv<T: Decodable>(&mut self) -> IoResult<T> {
         loop {
-            let addr = try!(recv_and_decode_1(&mut self.socket, &mut self.buf));
+            // Reliable messages can arrive together
+            let ready = self.conn.lock().unwrap().ready.pop_front();
+            match ready {
+                None => (),
+                Some(body) => match decode_body(body.as_slice()) {
+                    None       => continue,
+                    Some(body) => return Ok(body),
+                },
+            };
+
+            let (len, addr) = try!(recv_and_decode_1(&mut self.socket, &mut self.buf));
             if addr == self.connected_to {
-                let mut conn = self.conn.lock().unwrap();
-                match try!(recv_and_decode_2(conn.deref_mut(), addr, &mut self.socket, &mut self.buf)) {
+                match decode_header(self.buf.slice_to(len)) {
                     None => (),
-                    Some(body) => return Ok(body)
+                    Some((header, body)) => {
+                        let mut conn = self.conn.lock().unwrap();
+                        try!(recv_and_decode_2(conn.deref_mut(), addr, &mut self.socket, len, header, body));
+                    },
                 }
             } else {
                 warn!("Got message from unknown sender {}, expected {}", addr, self.connected_to);
@@ -302,6 +917,10 @@ impl ClientHandle {
     pub fn set_timeout(&mut self, ms: Option<u64>) {
         self.socket.set_timeout(ms)
     }
+
+    pub fn stats(&self) -> Stats {
+        self.conn.lock().unwrap().measure.stats
+    }
 }
 
 impl Drop for Client {
@@ -310,31 +929,177 @@ impl Drop for Client {
             None         => (),
             Some(ref tx) => tx.send(()).ok().unwrap(),
         }
+        let mut conn = self.handle.conn.lock().unwrap();
+        match send_disconnect(conn.deref_mut(), &mut self.handle.socket, self.handle.connected_to) {
+            Ok(()) => (),
+            Err(err) => debug!("network::Client::drop: could not disconnect: {}", err),
+        }
     }
 }
 
 // ---------------------------------------------------------------------
 // Server
 
+#[derive(PartialEq, Clone, Copy, Show)]
+pub enum DisconnectReason {
+    /// The client said goodbye
+    Quit,
+    TimedOut,
+}
+
+pub enum Event<T> {
+    Connect(SocketAddr),
+    Disconnect(SocketAddr, DisconnectReason),
+    Message(SocketAddr, T),
+}
+
+struct Clients {
+    conns: HashMap<SocketAddr, Conn>,
+    /// Clients that we challenged, with their salt, our salt, and when
+    /// they first asked.
+    challenges: HashMap<SocketAddr, (u64, u64, u32)>,
+    max_clients: usize,
+    clock: Clock,
+}
+
+impl Clients {
+    fn challenge(&mut self, sock: &mut Socket, addr: SocketAddr, proto_id: u32, salt: u64) -> IoResult<()> {
+        if proto_id != PROTO_ID {
+            info!("Rejecting {}: mismatching proto-id, got {}, expecting {}", addr, proto_id, PROTO_ID);
+            return send_control(sock, addr, proto_id, MsgType::Reject(RejectReason::ProtocolMismatch));
+        }
+        if self.conns.len() >= self.max_clients && !self.conns.contains_key(&addr) {
+            info!("Rejecting {}: server full", addr);
+            return send_control(sock, addr, PROTO_ID, MsgType::Reject(RejectReason::ServerFull));
+        }
+        let now = self.clock.ticks();
+        // Resent requests get the same challenge
+        let (server_salt, asked) = match self.challenges.get(&addr) {
+            Some(&(their_salt, server_salt, asked)) if their_salt == salt => (server_salt, asked),
+            _ => {
+                if self.challenges.len() >= MAX_PENDING_CONNS {
+                    warn!("Too many pending connections, dropping request from {}", addr);
+                    return Ok(());
+                }
+                (rand::random(), now)
+            },
+        };
+        let _ = self.challenges.insert(addr, (salt, server_salt, asked));
+        send_control(sock, addr, PROTO_ID, MsgType::Challenge(salt, server_salt))
+    }
+
+    // Returns whether a new connection was created.
+    fn accept(&mut self, sock: &mut Socket, addr: SocketAddr, proto_id: u32, response: u64) -> IoResult<bool> {
+        if proto_id != PROTO_ID {
+            warn!("Mismatching proto-id, got {}, expecting {}", proto_id, PROTO_ID);
+            return Ok(false);
+        }
+        if self.conns.contains_key(&addr) {
+            // Our `Accept` got lost
+            try!(send_control(sock, addr, PROTO_ID, MsgType::Accept));
+            return Ok(false);
+        }
+        let expected = match self.challenges.get(&addr) {
+            None => {
+                debug!("Challenge response from {}, which we did not challenge, dropping", addr);
+                return Ok(false);
+            },
+            Some(&(salt, server_salt, _)) => salt ^ server_salt,
+        };
+        if response != expected {
+            warn!("Wrong challenge response from {}, dropping", addr);
+            return Ok(false);
+        }
+        let _ = self.challenges.remove(&addr);
+        if self.conns.len() >= self.max_clients {
+            info!("Rejecting {}: server full", addr);
+            try!(send_control(sock, addr, PROTO_ID, MsgType::Reject(RejectReason::ServerFull)));
+            return Ok(false);
+        }
+        let _ = self.conns.insert(addr, Conn::new(self.clock.clone()));
+        info!("Accepted connection from {}", addr);
+        match send_control(sock, addr, PROTO_ID, MsgType::Accept) {
+            Ok(()) => (),
+            // They'll ask again
+            Err(err) => warn!("network::Server::recv: could not accept {}: {}", addr, err),
+        };
+        Ok(true)
+    }
+
+    fn pop_ready(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+        for (addr, conn) in self.conns.iter_mut() {
+            match conn.ready.pop_front() {
+                None       => (),
+                Some(body) => return Some((*addr, body)),
+            }
+        }
+        None
+    }
+
+    // Forgets stale challenges, and removes and returns a connection
+    // that timed out, if any.
+    fn sweep(&mut self) -> Option<SocketAddr> {
+        let now = self.clock.ticks();
+        let stale: Vec<SocketAddr> = self.challenges.iter()
+            .filter(|&(_, &(_, _, asked))| now - asked > CONNECT_TIMEOUT)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in stale.iter() {
+            let _ = self.challenges.remove(addr);
+        }
+        let timed_out = self.conns.iter()
+            .find(|&(_, conn)| now - conn.remote.received > CONN_TIMEOUT)
+            .map(|(addr, _)| *addr);
+        match timed_out {
+            None => None,
+            Some(addr) => {
+                debug!("Connection {} timed out", addr);
+                let _ = self.conns.remove(&addr);
+                Some(addr)
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Server {
-    socket: UdpSocket,
-    clients: Arc<Mutex<HashMap<SocketAddr, Conn>>>,
+    socket: Socket,
+    clients: Arc<Mutex<Clients>>,
+    // See `set_timeout`
+    timeout: Option<u64>,
 }
 
 impl Server {
-    pub fn new<A: ToSocketAddr>(addr: A) -> IoResult<Server> {
-        let sock = try!(UdpSocket::bind(addr));
+    pub fn new<A: ToSocketAddr>(addr: A, max_clients: usize) -> IoResult<Server> {
+        Server::conditioned(addr, max_clients, Link::perfect())
+    }
+
+    /// Like `new`, but everything we send and receive goes through
+    /// `link`, whoever it's from or to.
+    pub fn conditioned<A: ToSocketAddr>(addr: A, max_clients: usize, link: Link) -> IoResult<Server> {
+        Server::with_clock(addr, max_clients, link, Clock::real())
+    }
+
+    /// Like `conditioned`, with the time coming from `clock`.  Socket
+    /// timeouts are still in real time.
+    pub fn with_clock<A: ToSocketAddr>(addr: A, max_clients: usize, link: Link, clock: Clock) -> IoResult<Server> {
+        let sock = try!(Socket::bind(addr, link, clock.clone()));
         Ok(Server{
             socket: sock,
-            clients: Arc::new(Mutex::new(HashMap::new())),
+            clients: Arc::new(Mutex::new(Clients{
+                conns: HashMap::new(),
+                challenges: HashMap::new(),
+                max_clients: max_clients,
+                clock: clock,
+            })),
+            timeout: None,
         })
     }
 
-    pub fn send<T : Encodable>(&mut self, addr: SocketAddr, body: &T) -> IoResult<()> {
+    pub fn send<T : Encodable>(&mut self, addr: SocketAddr, body: &T, chan: Channel) -> IoResult<()> {
         let mut clients = self.clients.lock().unwrap();
-        match clients.entry(addr) {
-            Entry::Vacant(_) => {
+        match clients.conns.get_mut(&addr) {
+            None => {
                 error!("Sending to unknown client {}", addr);
                 Err(IoError{
                     kind: IoErrorKind::NotConnected,
@@ -342,131 +1107,193 @@ impl Server {
                     detail: Some(format!("Address received: {}", addr))
                 })
             },
-            Entry::Occupied(mut entry) => {
+            // If the connection timed out, `recv` will report it
+            Some(conn) => {
                 let mut buf = [0; MAX_PACKET_SIZE];
-                match encode_and_send(entry.get_mut(), &mut self.socket, &mut buf, addr, MsgType::Normal, body) {
-                    Err(err) => {
-                        match err.kind {
-                            IoErrorKind::Closed => {
-                                debug!("Removing connection to {}", addr);
-                                let _ = entry.remove();
-                            },
-                            _ => (),
-                        };
-                        Err(err)
-                    },
-                    Ok(()) => Ok(())
-                }
+                send_on(conn, &mut self.socket, &mut buf, addr, chan, body)
             }
         }
     }
 
-    pub fn recv<T: Decodable>(&mut self) -> IoResult<(SocketAddr, T)> {
+    /// Also reports clients connecting and disconnecting, including the
+    /// ones that time out.  Clients are only ever reported as
+    /// disconnected after being reported as connected.
+    pub fn recv<T: Decodable>(&mut self) -> IoResult<Event<T>> {
         let mut buf = [0; MAX_PACKET_SIZE];
+        // How long we've gone without getting anything, see `set_timeout`
+        let mut silent_for = 0;
         loop {
-            let addr = try!(recv_and_decode_1(&mut self.socket, &mut buf));
-            let body = {
-                let mut clients = self.clients.lock().unwrap();
-                // Create new connection if needed
-                match clients.entry(addr) {
-                    // TODO is there a nice way to float the conn out?
-                    // do I have to define a closure or another
-                    // function?
-                    Entry::Vacant(entry) => {
-                        let conn = entry.insert(Conn::new());
-                        try!(recv_and_decode_2(conn, addr, &mut self.socket, &mut buf))
-                    },
-                    Entry::Occupied(mut entry) => {
-                        let conn = entry.get_mut();
-                        try!(recv_and_decode_2(conn, addr, &mut self.socket, &mut buf))
+            match self.clients.lock().unwrap().sweep() {
+                None       => (),
+                Some(addr) => return Ok(Event::Disconnect(addr, DisconnectReason::TimedOut)),
+            }
+
+            let ready = self.clients.lock().unwrap().pop_ready();
+            match ready {
+                None => (),
+                Some((addr, body)) => match decode_body(body.as_slice()) {
+                    None       => continue,
+                    Some(body) => return Ok(Event::Message(addr, body)),
+                },
+            };
+
+            // Wake up now and then to notice timeouts and resend
+            // reliable messages even if nobody talks to us
+            let wait = match self.timeout {
+                None     => PING_INTERVAL as u64,
+                Some(ms) => min(ms, PING_INTERVAL as u64),
+            };
+            self.socket.set_timeout(Some(wait));
+            let (len, addr) = match recv_and_decode_1(&mut self.socket, &mut buf) {
+                Err(ref err) if err.kind == IoErrorKind::TimedOut => {
+                    let mut clients = self.clients.lock().unwrap();
+                    for (addr, conn) in clients.conns.iter_mut() {
+                        match resend(conn, &mut self.socket, *addr) {
+                            Ok(()) => (),
+                            Err(err) => debug!("network::Server::recv: could not resend to {}: {}", addr, err),
+                        }
                     }
-                }
+                    silent_for += wait;
+                    match self.timeout {
+                        Some(ms) if silent_for >= ms => return Err(err.clone()),
+                        _                            => continue,
+                    }
+                },
+                Err(err) => return Err(err),
+                Ok(received) => received,
+            };
+            silent_for = 0;
+            let (header, body) = match decode_header(buf.slice_to(len)) {
+                None    => continue,
+                Some(x) => x,
             };
-            match body {
-                None       => {},
-                Some(body) => return Ok((addr, body))
+
+            let mut clients = self.clients.lock().unwrap();
+            match header.msg_type {
+                MsgType::ConnectRequest(salt) =>
+                    try!(clients.challenge(&mut self.socket, addr, header.proto_id, salt)),
+                MsgType::ChallengeResponse(response) => {
+                    if try!(clients.accept(&mut self.socket, addr, header.proto_id, response)) {
+                        return Ok(Event::Connect(addr));
+                    }
+                },
+                _ => {
+                    let res = match clients.conns.get_mut(&addr) {
+                        None => {
+                            debug!("Got message from unknown sender {}, dropping", addr);
+                            Ok(())
+                        },
+                        Some(conn) => recv_and_decode_2(conn, addr, &mut self.socket, len, header, body),
+                    };
+                    match res {
+                        Ok(()) => (),
+                        Err(ref err) if err.kind == IoErrorKind::Closed => {
+                            let _ = clients.conns.remove(&addr);
+                            return Ok(Event::Disconnect(addr, DisconnectReason::Quit));
+                        },
+                        Err(err) => return Err(err),
+                    }
+                },
             }
         }
     }
 
+    /// With `Some(ms)`, `recv` gives up with a `TimedOut` error after
+    /// going `ms` milliseconds without getting anything.  Clones share
+    /// the socket but not the timeout.
+    pub fn set_timeout(&mut self, ms: Option<u64>) {
+        self.timeout = ms;
+    }
+
+    /// Drops the connection to `addr`, telling the client about it.
+    /// There will be no `Disconnect` event for it.
+    pub fn disconnect(&mut self, addr: SocketAddr) -> IoResult<()> {
+        let conn = self.clients.lock().unwrap().conns.remove(&addr);
+        match conn {
+            None => Err(IoError{
+                kind: IoErrorKind::NotConnected,
+                desc: "network::Server::disconnect: unknown address",
+                detail: Some(format!("Address received: {}", addr))
+            }),
+            Some(mut conn) => send_disconnect(&mut conn, &mut self.socket, addr),
+        }
+    }
+
+    /// `None` if there's no connection to `addr`.
+    pub fn stats(&self, addr: &SocketAddr) -> Option<Stats> {
+        let clients = self.clients.lock().unwrap();
+        clients.conns.get(addr).map(|conn| conn.measure.stats)
+    }
+
     pub fn active_conn(&self, addr: &SocketAddr) -> bool {
         let clients = self.clients.lock().unwrap();
-        clients.get(addr).is_some()
+        clients.conns.get(addr).is_some()
     }
 
     #[cfg(test)]
     fn get_conn(&self, addr: &SocketAddr) -> Option<Conn> {
         let clients = self.clients.lock().unwrap();
-        match clients.get(addr) {
+        match clients.conns.get(addr) {
             None       => None,
-            Some(conn) => Some(*conn),
+            Some(conn) => Some(conn.clone()),
         }
     }
 }
 
-// ---------------------------------------------------------------------
-// Multiple seqs
-
-// #[derive(PartialEq, Clone, Copy, Show, RustcDecodable, RustcEncodable)]
-// struct RemoteSeqs {
-//     last: Seq,
-//     // A bitfield that records whether the previous 32 messages were
-//     // received
-//     previous: u64,
-// }
-
-// impl RemoteSeqs {
-//     fn update(&mut self, new: Seq) {
-//         let (recent, diff) = more_recent(new, self.last);
-//         let diff = diff as uint;
-//         if recent {
-//             self.last = new;
-//             self.previous = (self.previous >> diff) | (1 >> diff-1);
-//         } else {
-//             if diff > 0 {
-//                 self.previous = self.previous | 1 >> diff-1;
-//             }
-//         }
-//     }
-// }
-
 // ---------------------------------------------------------------------
 // Tests
 
+#[cfg(test)]
+fn connect(server: &Server, server_addr: SocketAddr, client_addr: SocketAddr) -> Client {
+    let mut server = server.clone();
+    let guard = Thread::scoped(move || server.recv::<isize>().ok().unwrap());
+    let client = Client::new(server_addr, client_addr, false).ok().unwrap();
+    match guard.join().ok().unwrap() {
+        Event::Connect(addr) => assert!(addr == client_addr),
+        _                    => panic!("Expected a connection"),
+    }
+    client
+}
+
 #[test]
 fn test() {
     let server_addr = "127.0.0.1:10000".to_socket_addr().ok().unwrap();
     let client_addr = "127.0.0.1:10001".to_socket_addr().ok().unwrap();
-    let mut server = Server::new(server_addr).ok().unwrap();
-    let mut client = Client::new(server_addr, client_addr, false).ok().unwrap();
+    let mut server = Server::new(server_addr, MAX_CLIENTS).ok().unwrap();
+    let client = connect(&server, server_addr, client_addr);
     let mut client_handle = client.handle();
 
     let body: isize = 1234;
-    client_handle.send(&body).ok().unwrap();
+    client_handle.send(&body, Channel::Unreliable).ok().unwrap();
     {
         let conn = client_handle.conn.lock().unwrap();
         assert!(conn.local.seq == Seq(1));
-        assert!(conn.local.ack == Seq(0));
-        assert!(conn.remote.ack == Seq(0));
+        assert!(conn.local.ack.last == Seq(0));
+        assert!(conn.remote.ack.last == Seq(0));
     }
 
-    let (recv_addr, recv_body): (SocketAddr, isize) = server.recv().ok().unwrap();
-    assert!(recv_body == body);
-    assert!(recv_addr == client_addr);
+    match server.recv().ok().unwrap() {
+        Event::Message(recv_addr, recv_body) => {
+            let recv_body: isize = recv_body;
+            assert!(recv_body == body);
+            assert!(recv_addr == client_addr);
+        },
+        _ => panic!("Expected a message"),
+    }
     {
         let server_client_conn = server.get_conn(&client_addr).unwrap();
         assert!(server_client_conn.local.seq == Seq(0));
-        assert!(server_client_conn.local.ack == Seq(1));
-        assert!(server_client_conn.remote.ack == Seq(0));
+        assert!(server_client_conn.local.ack.last == Seq(1));
+        assert!(server_client_conn.remote.ack.last == Seq(0));
     }
 
     let body: isize = 4321;
-    server.send(client_addr, &body).ok().unwrap();
+    server.send(client_addr, &body, Channel::Unreliable).ok().unwrap();
     {
         let server_client_conn = server.get_conn(&client_addr).unwrap();
-        assert!(server_client_conn.local.ack == Seq(1));
+        assert!(server_client_conn.local.ack.last == Seq(1));
         assert!(server_client_conn.local.seq == Seq(1));
-        assert!(server_client_conn.remote.ack == Seq(0));
+        assert!(server_client_conn.remote.ack.last == Seq(0));
     }
 
     let recv_body: isize = client_handle.recv().ok().unwrap();
@@ -474,6 +1301,314 @@ fn test() {
     {
         let conn = client_handle.conn.lock().unwrap();
         assert!(conn.local.seq == Seq(1));
-        assert!(conn.local.ack == Seq(1));
+        assert!(conn.local.ack.last == Seq(1));
+    }
+}
+
+#[test]
+fn test_disconnect() {
+    let server_addr = "127.0.0.1:10002".to_socket_addr().ok().unwrap();
+    let client_addr = "127.0.0.1:10003".to_socket_addr().ok().unwrap();
+    let mut server = Server::new(server_addr, MAX_CLIENTS).ok().unwrap();
+    let client = connect(&server, server_addr, client_addr);
+    assert!(server.active_conn(&client_addr));
+
+    drop(client);
+    match server.recv::<isize>().ok().unwrap() {
+        Event::Disconnect(addr, reason) => {
+            assert!(addr == client_addr);
+            assert!(reason == DisconnectReason::Quit);
+        },
+        _ => panic!("Expected a disconnection"),
+    }
+    assert!(!server.active_conn(&client_addr));
+}
+
+#[test]
+fn test_timeout() {
+    let server_addr = "127.0.0.1:10022".to_socket_addr().ok().unwrap();
+    let client_addr = "127.0.0.1:10023".to_socket_addr().ok().unwrap();
+    let clock = Clock::manual();
+    let mut server = Server::with_clock(server_addr, MAX_CLIENTS, Link::perfect(), clock.clone()).ok().unwrap();
+    let _client = connect(&server, server_addr, client_addr);
+
+    // The client doesn't ping, and no time has to actually go by
+    clock.advance(CONN_TIMEOUT + 1);
+    match server.recv::<isize>().ok().unwrap() {
+        Event::Disconnect(addr, reason) => {
+            assert!(addr == client_addr);
+            assert!(reason == DisconnectReason::TimedOut);
+        },
+        _ => panic!("Expected a disconnection"),
+    }
+}
+
+#[test]
+fn test_server_full() {
+    let server_addr = "127.0.0.1:10004".to_socket_addr().ok().unwrap();
+    let client_addr = "127.0.0.1:10005".to_socket_addr().ok().unwrap();
+    let mut server = Server::new(server_addr, 0).ok().unwrap();
+    // Nobody gets in, so this only returns once the client is gone
+    server.set_timeout(Some(CONNECT_RETRY as u64));
+    let guard = Thread::scoped(move || server.recv::<isize>());
+
+    match Client::new(server_addr, client_addr, false) {
+        Ok(_) => panic!("Expected the connection to be rejected"),
+        Err(err) => {
+            assert!(err.kind == IoErrorKind::ConnectionRefused);
+            assert!(err.detail == Some(format!("{:?}", RejectReason::ServerFull)));
+        },
+    }
+    match guard.join().ok().unwrap() {
+        Err(err) => assert!(err.kind == IoErrorKind::TimedOut),
+        Ok(_)    => panic!("Expected the server to time out"),
+    }
+}
+
+#[test]
+fn test_seq_wrap() {
+    let mut seq = Seq(u32::MAX - 1);
+    seq.bump();
+    assert!(seq == Seq(u32::MAX));
+    seq.bump();
+    assert!(seq == Seq(0));
+
+    assert!(Seq(0).newer_than(Seq(u32::MAX)));
+    assert!(!Seq(u32::MAX).newer_than(Seq(0)));
+    assert!(Seq(5).newer_than(Seq(u32::MAX - 5)));
+    assert!(!Seq(3).newer_than(Seq(3)));
+    assert!(Seq(0).distance(Seq(u32::MAX)) == 1);
+}
+
+#[test]
+fn test_acks_wrap() {
+    let start = u32::MAX - 20;
+    let mut acks = Acks{last: Seq(start), previous: 0};
+    let mut seq = Seq(start);
+    let mut sent: Vec<Seq> = Vec::new();
+    // Lose every third packet, and deliver the others in pairs swapped
+    // around, across the wrap.
+    for i in range(0, 40) {
+        seq.bump();
+        sent.push(seq);
+        if i % 2 == 1 {
+            let (fst, snd) = (sent[sent.len() - 1], sent[sent.len() - 2]);
+            if i % 3 != 0 { acks.insert(fst) };
+            if (i - 1) % 3 != 0 { acks.insert(snd) };
+        }
+    }
+    // The last one was lost
+    assert!(acks.last == Seq(18));
+    for (i, seq) in sent.iter().enumerate() {
+        // The first few are too old to be in the bitfield
+        if acks.last.distance(*seq) <= ACK_BITS {
+            assert!(acks.contains(*seq) == (i % 3 != 0));
+        }
+    }
+    assert!(!acks.contains(Seq(19)));
+
+    // The remote learns the same thing from our headers, even if they
+    // arrive out of order.
+    let mut remote = Acks{last: Seq(start), previous: 0};
+    let behind = acks.last.distance(Seq(u32::MAX));
+    let older = Acks{last: Seq(u32::MAX), previous: acks.previous >> behind as usize};
+    remote.merge(&acks);
+    remote.merge(&older);
+    assert!(remote == acks);
+
+    // Whichever older header comes in late, there's nothing new in it
+    let mut seq = acks.last;
+    for n in range(1, ACK_BITS + 1) {
+        seq = Seq(seq.0.checked_sub(1).unwrap_or(u32::MAX));
+        if !acks.contains(seq) { continue };
+        let previous = if n >= ACK_BITS { 0 } else { acks.previous >> n as usize };
+        let mut remote = acks;
+        remote.merge(&Acks{last: seq, previous: previous});
+        assert!(remote == acks);
+    }
+}
+
+#[test]
+fn test_receive_reliable() {
+    let mut conn = Conn::new(Clock::manual());
+    // Duplicated and out of order
+    for &(seq, body) in [(1, 1u8), (0, 0), (1, 1), (3, 3), (0, 0), (2, 2)].iter() {
+        conn.receive_reliable(Seq(seq), &[body]);
+    }
+    let ready: Vec<Vec<u8>> = conn.ready.into_iter().collect();
+    assert!(ready == vec![vec![0], vec![1], vec![2], vec![3]]);
+    assert!(conn.reliable_early.is_empty());
+    assert!(conn.local.reliable_next == Seq(4));
+}
+
+#[test]
+fn test_reliable() {
+    let server_addr = "127.0.0.1:10006".to_socket_addr().ok().unwrap();
+    let client_addr = "127.0.0.1:10007".to_socket_addr().ok().unwrap();
+    let mut server = Server::new(server_addr, MAX_CLIENTS).ok().unwrap();
+    let client = connect(&server, server_addr, client_addr);
+    let mut client_handle = client.handle();
+
+    for body in range(0, 5is) {
+        client_handle.send(&body, Channel::Reliable).ok().unwrap();
+    }
+    assert!(client_handle.conn.lock().unwrap().unacked.len() == 5);
+    for expected in range(0, 5is) {
+        match server.recv().ok().unwrap() {
+            Event::Message(_, body) => {
+                let body: isize = body;
+                assert!(body == expected);
+            },
+            _ => panic!("Expected a message"),
+        }
+    }
+
+    // Once the server tells us that it got them, we stop resending
+    server.send(client_addr, &0is, Channel::Unreliable).ok().unwrap();
+    let _: isize = client_handle.recv().ok().unwrap();
+    client_handle.send(&5is, Channel::Unreliable).ok().unwrap();
+    assert!(client_handle.conn.lock().unwrap().unacked.is_empty());
+}
+
+#[test]
+fn test_reliable_big() {
+    let server_addr = "127.0.0.1:10024".to_socket_addr().ok().unwrap();
+    let client_addr = "127.0.0.1:10025".to_socket_addr().ok().unwrap();
+    let mut server = Server::new(server_addr, MAX_CLIENTS).ok().unwrap();
+    let client = connect(&server, server_addr, client_addr);
+    let mut client_handle = client.handle();
+
+    // In more packets than a header can ack
+    let body: Vec<u8> = range(0, 40 * FRAGMENT_SIZE).map(|i| i as u8).collect();
+    client_handle.send(&body, Channel::Reliable).ok().unwrap();
+    match server.recv().ok().unwrap() {
+        Event::Message(_, recv_body) => {
+            let recv_body: Vec<u8> = recv_body;
+            assert!(recv_body == body);
+        },
+        _ => panic!("Expected a message"),
+    }
+
+    server.send(client_addr, &0is, Channel::Unreliable).ok().unwrap();
+    let _: isize = client_handle.recv().ok().unwrap();
+    assert!(client_handle.conn.lock().unwrap().unacked.is_empty());
+}
+
+#[test]
+fn test_fragments() {
+    let server_addr = "127.0.0.1:10008".to_socket_addr().ok().unwrap();
+    let client_addr = "127.0.0.1:10009".to_socket_addr().ok().unwrap();
+    let mut server = Server::new(server_addr, MAX_CLIENTS).ok().unwrap();
+    let client = connect(&server, server_addr, client_addr);
+    let mut client_handle = client.handle();
+
+    let body: Vec<u32> = range(0, 5 * MAX_PACKET_SIZE as u32).collect();
+    client_handle.send(&body, Channel::Unreliable).ok().unwrap();
+    client_handle.send(&body, Channel::Reliable).ok().unwrap();
+    for _ in range(0, 2us) {
+        match server.recv().ok().unwrap() {
+            Event::Message(_, recv_body) => {
+                let recv_body: Vec<u32> = recv_body;
+                assert!(recv_body == body);
+            },
+            _ => panic!("Expected a message"),
+        }
+    }
+    assert!(server.get_conn(&client_addr).unwrap().partials.is_empty());
+}
+
+#[test]
+fn test_fragments_timeout() {
+    let clock = Clock::manual();
+    let mut conn = Conn::new(clock.clone());
+    let fragment = Fragment{id: Seq(0), index: 1, count: 2, reliable: None};
+    conn.receive_fragment(fragment, &[1]);
+    assert!(conn.partials.len() == 1);
+    // The first piece shows up too late
+    clock.advance(FRAGMENT_TIMEOUT + 10);
+    conn.receive_fragment(Fragment{id: Seq(1), index: 0, count: 1, reliable: None}, &[2]);
+    conn.receive_fragment(Fragment{index: 0, ..fragment}, &[0]);
+    let ready: Vec<Vec<u8>> = conn.ready.into_iter().collect();
+    assert!(ready == vec![vec![2]]);
+    assert!(conn.partials.len() == 1);
+}
+
+#[test]
+fn test_fragments_limit() {
+    let clock = Clock::manual();
+    let mut conn = Conn::new(clock.clone());
+    for i in range(0, MAX_PARTIALS as u32 + 1) {
+        conn.receive_fragment(Fragment{id: Seq(i), index: 0, count: 2, reliable: None}, &[0]);
+        clock.advance(1);
+    }
+    // The oldest one made room for the last
+    assert!(conn.partials.len() == MAX_PARTIALS);
+    assert!(!conn.partials.contains_key(&Seq(0)));
+    let last = Seq(MAX_PARTIALS as u32);
+    conn.receive_fragment(Fragment{id: last, index: 1, count: 2, reliable: None}, &[1]);
+    // The dropped one starts from scratch
+    conn.receive_fragment(Fragment{id: Seq(0), index: 1, count: 2, reliable: None}, &[1]);
+    let ready: Vec<Vec<u8>> = conn.ready.into_iter().collect();
+    assert!(ready == vec![vec![0, 1]]);
+    assert!(conn.partials.len() == MAX_PARTIALS);
+}
+
+#[test]
+fn test_measure() {
+    let mut measure = Measure::new();
+    for i in range(1, 11u32) {
+        measure.sent(Seq(i), 100, 1000);
+    }
+    assert!(measure.stats.bytes_sent == 1000);
+
+    // All but the first one got there
+    let mut acks = Acks::new();
+    for i in range(2, 11u32) {
+        acks.insert(Seq(i));
+    }
+    measure.acked(&acks, 1100);
+    assert!(measure.stats.rtt == 100.);
+    assert!(measure.stats.loss == 0.);
+    assert!(measure.in_flight.len() == 1);
+
+    // It's lost once it's too old to be acked
+    acks.insert(Seq(10 + ACK_BITS));
+    measure.acked(&acks, 1200);
+    assert!(measure.stats.loss > 0.);
+    assert!(measure.in_flight.is_empty());
+}
+
+#[test]
+fn test_conditioned() {
+    let server_addr = "127.0.0.1:10020".to_socket_addr().ok().unwrap();
+    let client_addr = "127.0.0.1:10021".to_socket_addr().ok().unwrap();
+    let link = Link{
+        outgoing: Conditions{latency: 50, ..Conditions::perfect()},
+        incoming: Conditions{latency: 50, ..Conditions::perfect()},
+    };
+    let mut server = Server::new(server_addr, MAX_CLIENTS).ok().unwrap();
+    let guard = {
+        let mut server = server.clone();
+        Thread::scoped(move || server.recv::<isize>().ok().unwrap())
+    };
+    let client = Client::conditioned(server_addr, client_addr, false, link).ok().unwrap();
+    match guard.join().ok().unwrap() {
+        Event::Connect(addr) => assert!(addr == client_addr),
+        _                    => panic!("Expected a connection"),
+    }
+    let mut client_handle = client.handle();
+
+    client_handle.send(&1is, Channel::Unreliable).ok().unwrap();
+    match server.recv().ok().unwrap() {
+        Event::Message(_, body) => {
+            let body: isize = body;
+            assert!(body == 1);
+        },
+        _ => panic!("Expected a message"),
     }
+    server.send(client_addr, &2is, Channel::Unreliable).ok().unwrap();
+    let body: isize = client_handle.recv().ok().unwrap();
+    assert!(body == 2);
+    // The answer acked our message, a round trip through the link later
+    assert!(client_handle.stats().rtt >= 100.);
 }
@@ -0,0 +1,439 @@
+// A link conditioner, to see how the game copes with a bad network
+// without needing one: packets going through a `Socket` can be held
+// back, lost, duplicated and reordered, separately in each direction.
+
+use std::io::net::udp::UdpSocket;
+use std::io::net::ip::{SocketAddr, ToSocketAddr};
+use std::io::{IoError, IoResult, IoErrorKind};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread::Thread;
+use std::str::FromStr;
+use std::slice::bytes::copy_memory;
+use std::cmp::{max, min};
+use std::rand;
+
+use clock::Clock;
+
+/// What happens to the packets going one way.  `latency` and `jitter`
+/// are in milliseconds, the rest are chances from 0 to 1.
+#[derive(PartialEq, Clone, Copy, Show)]
+pub struct Conditions {
+    pub latency: u32,
+    /// Each packet is held back up to this much on top of `latency`
+    pub jitter: u32,
+    pub loss: f32,
+    pub duplicate: f32,
+    /// A reordered packet is held back until the next one overtakes it,
+    /// or for `REORDER_WAIT` if none comes along
+    pub reorder: f32,
+}
+
+impl Conditions {
+    pub fn perfect() -> Conditions {
+        Conditions{latency: 0, jitter: 0, loss: 0., duplicate: 0., reorder: 0.}
+    }
+
+    #[inline]
+    pub fn is_perfect(&self) -> bool {
+        *self == Conditions::perfect()
+    }
+}
+
+/// Comma separated settings, e.g. `latency=100,jitter=20,loss=0.05`.
+/// The ones left out are perfect.
+impl FromStr for Conditions {
+    fn from_str(s: &str) -> Option<Conditions> {
+        let mut conditions = Conditions::perfect();
+        for setting in s.split(',').map(|setting| setting.trim()).filter(|setting| !setting.is_empty()) {
+            let eq = match setting.find('=') {
+                None     => return None,
+                Some(eq) => eq,
+            };
+            let (key, value) = (setting.slice_to(eq).trim(), setting.slice_from(eq + 1).trim());
+            match key {
+                "latency" => match value.parse() {
+                    None          => return None,
+                    Some(latency) => conditions.latency = latency,
+                },
+                "jitter" => match value.parse() {
+                    None         => return None,
+                    Some(jitter) => conditions.jitter = jitter,
+                },
+                "loss" => match parse_chance(value) {
+                    None       => return None,
+                    Some(loss) => conditions.loss = loss,
+                },
+                "duplicate" => match parse_chance(value) {
+                    None            => return None,
+                    Some(duplicate) => conditions.duplicate = duplicate,
+                },
+                "reorder" => match parse_chance(value) {
+                    None          => return None,
+                    Some(reorder) => conditions.reorder = reorder,
+                },
+                _ => return None,
+            }
+        }
+        Some(conditions)
+    }
+}
+
+fn parse_chance(s: &str) -> Option<f32> {
+    match s.parse() {
+        Some(chance) if chance >= 0. && chance <= 1. => Some(chance),
+        _                                            => None,
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Show)]
+pub struct Link {
+    /// For the packets we send
+    pub outgoing: Conditions,
+    /// For the packets we receive
+    pub incoming: Conditions,
+}
+
+impl Link {
+    pub fn perfect() -> Link {
+        Link{outgoing: Conditions::perfect(), incoming: Conditions::perfect()}
+    }
+}
+
+// ---------------------------------------------------------------------
+// Conditioning
+
+// How long past when it was due a reordered packet waits to be
+// overtaken, so that the last packet sent still gets through.
+const REORDER_WAIT: u32 = 100;
+
+#[derive(Clone)]
+struct Delayed {
+    /// In milliseconds, as given by the socket's `Clock`
+    due: u32,
+    bytes: Vec<u8>,
+    addr: SocketAddr,
+    /// Waiting to be overtaken
+    held: bool,
+}
+
+#[inline]
+fn happens(chance: f32) -> bool {
+    chance > 0. && rand::random::<f32>() < chance
+}
+
+// Keeps the packets in the order they're due, and the ones due at the
+// same time in the order they came.  A held packet is queued
+// `REORDER_WAIT` late, and comes out right after the one which
+// overtakes it if that's sooner.
+fn enqueue(queue: &mut Vec<Delayed>, packet: Delayed) {
+    let (due, held) = (packet.due, packet.held);
+    insert_due(queue, packet);
+    if held { return };
+    match queue.iter().position(|queued| queued.held) {
+        None => (),
+        Some(pos) => {
+            let overtaken = queue.remove(pos);
+            let overtaken_due = max(due, overtaken.due - REORDER_WAIT);
+            insert_due(queue, Delayed{due: overtaken_due, held: false, ..overtaken});
+        },
+    };
+}
+
+fn insert_due(queue: &mut Vec<Delayed>, packet: Delayed) {
+    let len = queue.len();
+    let pos = queue.iter().position(|queued| queued.due > packet.due).unwrap_or(len);
+    queue.insert(pos, packet);
+}
+
+struct Conditioner {
+    conditions: Conditions,
+    /// Whether the last packet was held, for the next one to overtake
+    holding: bool,
+}
+
+impl Conditioner {
+    fn new(conditions: Conditions) -> Conditioner {
+        Conditioner{conditions: conditions, holding: false}
+    }
+
+    // What comes out of a packet going through at `now`: nothing if it
+    // gets lost, and more than one copy if it gets duplicated.
+    fn condition(&mut self, bytes: Vec<u8>, addr: SocketAddr, now: u32) -> Vec<Delayed> {
+        let conditions = self.conditions;
+        let mut packets = Vec::new();
+        if happens(conditions.loss) { return packets };
+        let jitter = if conditions.jitter == 0 { 0 } else { rand::random::<u32>() % (conditions.jitter + 1) };
+        let packet = Delayed{due: now + conditions.latency + jitter, bytes: bytes, addr: addr, held: false};
+        if happens(conditions.duplicate) {
+            packets.push(packet.clone());
+        }
+        self.holding = !self.holding && happens(conditions.reorder);
+        if self.holding {
+            packets.push(Delayed{due: packet.due + REORDER_WAIT, held: true, ..packet});
+        } else {
+            packets.push(packet);
+        }
+        packets
+    }
+}
+
+struct Outgoing {
+    conditioner: Conditioner,
+    /// To the thread sending the packets when they're due
+    deliver: Sender<Delayed>,
+}
+
+struct Incoming {
+    conditioner: Conditioner,
+    queue: Vec<Delayed>,
+}
+
+impl Incoming {
+    fn receive(&mut self, bytes: Vec<u8>, addr: SocketAddr, now: u32) {
+        for packet in self.conditioner.condition(bytes, addr, now).into_iter() {
+            enqueue(&mut self.queue, packet);
+        }
+    }
+
+    fn pop_due(&mut self, now: u32) -> Option<Delayed> {
+        if self.queue.first().map_or(false, |packet| packet.due <= now) {
+            Some(self.queue.remove(0))
+        } else {
+            None
+        }
+    }
+}
+
+// Sends the outgoing packets when they're due.  Once every handle to
+// the socket is gone it sends what's left and stops.
+fn deliver_worker(sock: UdpSocket, packets: Receiver<Delayed>, clock: Clock) {
+    let mut sock = sock;
+    let _ = Thread::spawn(move || {
+        let mut queue: Vec<Delayed> = Vec::new();
+        let mut open = true;
+        while open || !queue.is_empty() {
+            if open && queue.is_empty() {
+                match packets.recv() {
+                    Ok(packet) => enqueue(&mut queue, packet),
+                    Err(_)     => break,
+                }
+            }
+            while open {
+                match packets.try_recv() {
+                    Ok(packet)                      => enqueue(&mut queue, packet),
+                    Err(TryRecvError::Empty)        => break,
+                    Err(TryRecvError::Disconnected) => open = false,
+                }
+            }
+            let now = clock.ticks();
+            while queue.first().map_or(false, |packet| packet.due <= now) {
+                let packet = queue.remove(0);
+                match sock.send_to(packet.bytes.as_slice(), packet.addr) {
+                    Ok(()) => (),
+                    Err(err) => debug!("network::link::deliver_worker: could not send to {}: {}", packet.addr, err),
+                }
+            }
+            clock.delay(1);
+        }
+    });
+}
+
+// ---------------------------------------------------------------------
+// Socket
+
+/// A `UdpSocket` going through a `Link`.  Clones share the conditions,
+/// and the packets waiting to go through.  Packets are held back
+/// according to the `Clock`, but timeouts are in real time as far as
+/// the OS is concerned.
+#[derive(Clone)]
+pub struct Socket {
+    sock: UdpSocket,
+    clock: Clock,
+    timeout: Option<u64>,
+    /// `None` where the link is perfect, so that we don't get in the way
+    outgoing: Option<Arc<Mutex<Outgoing>>>,
+    incoming: Option<Arc<Mutex<Incoming>>>,
+}
+
+impl Socket {
+    pub fn bind<A: ToSocketAddr>(addr: A, link: Link, clock: Clock) -> IoResult<Socket> {
+        let sock = try!(UdpSocket::bind(addr));
+        let outgoing = if link.outgoing.is_perfect() {
+            None
+        } else {
+            let (tx, rx) = channel();
+            deliver_worker(sock.clone(), rx, clock.clone());
+            Some(Arc::new(Mutex::new(Outgoing{conditioner: Conditioner::new(link.outgoing), deliver: tx})))
+        };
+        let incoming = if link.incoming.is_perfect() {
+            None
+        } else {
+            Some(Arc::new(Mutex::new(Incoming{conditioner: Conditioner::new(link.incoming), queue: Vec::new()})))
+        };
+        Ok(Socket{sock: sock, clock: clock, timeout: None, outgoing: outgoing, incoming: incoming})
+    }
+
+    pub fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> IoResult<()> {
+        match self.outgoing {
+            None => self.sock.send_to(buf, addr),
+            Some(ref outgoing) => {
+                let mut outgoing = outgoing.lock().unwrap();
+                let packets = outgoing.conditioner.condition(buf.to_vec(), addr, self.clock.ticks());
+                for packet in packets.into_iter() {
+                    // The worker lasts as long as we do
+                    outgoing.deliver.send(packet).ok().unwrap();
+                }
+                Ok(())
+            },
+        }
+    }
+
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(usize, SocketAddr)> {
+        let incoming = match self.incoming {
+            None               => return self.sock.recv_from(buf),
+            Some(ref incoming) => incoming.clone(),
+        };
+        let started = self.clock.ticks();
+        loop {
+            let now = self.clock.ticks();
+            let (ready, next_due) = {
+                let mut incoming = incoming.lock().unwrap();
+                let ready = incoming.pop_due(now);
+                (ready, incoming.queue.first().map(|packet| packet.due))
+            };
+            match ready {
+                None => (),
+                Some(packet) => {
+                    let len = min(buf.len(), packet.bytes.len());
+                    copy_memory(buf, packet.bytes.slice_to(len));
+                    return Ok((len, packet.addr));
+                },
+            };
+
+            // Wait for a packet to arrive, or for the next one we have
+            // to be due, or to time out
+            let mut wait = match self.timeout {
+                None => None,
+                Some(timeout) => {
+                    let elapsed = (now - started) as u64;
+                    if elapsed >= timeout {
+                        return Err(IoError{
+                            kind: IoErrorKind::TimedOut,
+                            desc: "network::link::Socket::recv_from: Timed out",
+                            detail: None,
+                        });
+                    }
+                    Some(timeout - elapsed)
+                },
+            };
+            match next_due {
+                None => (),
+                Some(due) => {
+                    let until = if due > now { (due - now) as u64 } else { 1 };
+                    wait = Some(wait.map_or(until, |wait| min(wait, until)));
+                },
+            };
+            self.sock.set_timeout(wait);
+            match self.sock.recv_from(buf) {
+                Ok((len, addr)) => incoming.lock().unwrap().receive(buf.slice_to(len).to_vec(), addr, self.clock.ticks()),
+                Err(ref err) if err.kind == IoErrorKind::TimedOut => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn set_timeout(&mut self, ms: Option<u64>) {
+        self.timeout = ms;
+        self.sock.set_timeout(ms)
+    }
+}
+
+// ---------------------------------------------------------------------
+// Tests
+
+#[cfg(test)]
+fn loopback(port: u16, from: Link, to: Link, clock: &Clock) -> (Socket, Socket, SocketAddr) {
+    let from = Socket::bind(("127.0.0.1", port), from, clock.clone()).ok().unwrap();
+    let to_addr = ("127.0.0.1", port + 1).to_socket_addr().ok().unwrap();
+    let mut to = Socket::bind(to_addr, to, clock.clone()).ok().unwrap();
+    to.set_timeout(Some(500));
+    (from, to, to_addr)
+}
+
+#[cfg(test)]
+fn recv_byte(sock: &mut Socket) -> Option<u8> {
+    let mut buf = [0; 16];
+    match sock.recv_from(&mut buf) {
+        Ok((len, _)) => { assert!(len == 1); Some(buf[0]) },
+        Err(ref err) if err.kind == IoErrorKind::TimedOut => None,
+        Err(err) => panic!("{}", err),
+    }
+}
+
+#[test]
+fn test_conditions_from_str() {
+    let conditions: Conditions = FromStr::from_str("latency=100, jitter=20,loss=0.05").unwrap();
+    assert!(conditions == Conditions{latency: 100, jitter: 20, loss: 0.05, ..Conditions::perfect()});
+    let empty: Option<Conditions> = FromStr::from_str("");
+    assert!(empty == Some(Conditions::perfect()));
+    let bad: Option<Conditions> = FromStr::from_str("loss=2");
+    assert!(bad.is_none());
+    let bad: Option<Conditions> = FromStr::from_str("speed=fast");
+    assert!(bad.is_none());
+}
+
+#[test]
+fn test_link_latency() {
+    let outgoing = Link{outgoing: Conditions{latency: 50, ..Conditions::perfect()}, ..Link::perfect()};
+    let incoming = Link{incoming: Conditions{latency: 50, ..Conditions::perfect()}, ..Link::perfect()};
+    let clock = Clock::real();
+    let (mut from, mut to, to_addr) = loopback(10010, outgoing, incoming, &clock);
+    let sent = clock.ticks();
+    from.send_to(&[1], to_addr).ok().unwrap();
+    assert!(recv_byte(&mut to) == Some(1));
+    assert!(clock.ticks() - sent >= 100);
+}
+
+#[test]
+fn test_link_loss() {
+    let link = Link{outgoing: Conditions{loss: 1., ..Conditions::perfect()}, ..Link::perfect()};
+    let (mut from, mut to, to_addr) = loopback(10012, link, Link::perfect(), &Clock::real());
+    from.send_to(&[1], to_addr).ok().unwrap();
+    assert!(recv_byte(&mut to).is_none());
+}
+
+#[test]
+fn test_link_duplicate_reorder() {
+    let link = Link{outgoing: Conditions{duplicate: 1., ..Conditions::perfect()}, ..Link::perfect()};
+    let (mut from, mut to, to_addr) = loopback(10014, link, Link::perfect(), &Clock::real());
+    from.send_to(&[1], to_addr).ok().unwrap();
+    assert!(recv_byte(&mut to) == Some(1));
+    assert!(recv_byte(&mut to) == Some(1));
+
+    // Every other packet gets overtaken, on the way in this time
+    let link = Link{incoming: Conditions{reorder: 1., ..Conditions::perfect()}, ..Link::perfect()};
+    let (mut from, mut to, to_addr) = loopback(10016, Link::perfect(), link, &Clock::real());
+    for byte in range(1, 5u8) {
+        from.send_to(&[byte], to_addr).ok().unwrap();
+    }
+    let received: Vec<Option<u8>> = range(0, 4us).map(|_| recv_byte(&mut to)).collect();
+    assert!(received == vec![Some(2), Some(1), Some(4), Some(3)]);
+}
+
+#[test]
+fn test_link_reorder_last() {
+    // The last packet is held, but it gets through with nothing to
+    // overtake it
+    let link = Link{incoming: Conditions{reorder: 1., ..Conditions::perfect()}, ..Link::perfect()};
+    let clock = Clock::real();
+    let (mut from, mut to, to_addr) = loopback(10018, Link::perfect(), link, &clock);
+    for byte in range(1, 4u8) {
+        from.send_to(&[byte], to_addr).ok().unwrap();
+    }
+    assert!(recv_byte(&mut to) == Some(2));
+    assert!(recv_byte(&mut to) == Some(1));
+    let waiting = clock.ticks();
+    assert!(recv_byte(&mut to) == Some(3));
+    assert!(clock.ticks() - waiting <= REORDER_WAIT + 100);
+}
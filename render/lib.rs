@@ -7,7 +7,8 @@ extern crate actors;
 extern crate conf;
 
 use sdl2::SdlResult;
-use sdl2::render::Renderer;
+use sdl2::render::{Renderer, Texture};
+use std::collections::HashMap;
 use std::ops::Deref;
 
 use geometry::*;
@@ -15,6 +16,29 @@ use specs::*;
 use actors::*;
 use conf::*;
 
+mod font;
+
+pub type Textures = HashMap<TextureId, Texture>;
+
+// The game crates don't know about SDL, so we convert here
+
+fn sdl_point(v: Vec2) -> sdl2::rect::Point {
+    sdl2::rect::Point{x: v.x as i32, y: v.y as i32}
+}
+
+fn sdl_rect(rect: &Rect) -> sdl2::rect::Rect {
+    sdl2::rect::Rect {
+        x: rect.pos.x as i32,
+        y: rect.pos.y as i32,
+        w: rect.w as i32,
+        h: rect.h as i32,
+    }
+}
+
+fn sdl_color(color: Color) -> sdl2::pixels::Color {
+    sdl2::pixels::Color::RGB(color.0, color.1, color.2)
+}
+
 pub struct RenderEnv {
     pub textures: Textures,
     pub renderer: Renderer,
@@ -30,15 +54,15 @@ impl RenderEnv {
         };
         let angle = from_radians(trans.rotation);
         self.renderer.copy_ex(
-            texture, Some(sprite.rect.sdl_rect()), Some(dst.sdl_rect()), ((sprite.angle - angle) as f64),
-            Some(sprite.center.point()), sdl2::render::RendererFlip::None)
+            texture, Some(sdl_rect(&sprite.rect)), Some(sdl_rect(&dst)), ((sprite.angle - angle) as f64),
+            Some(sdl_point(sprite.center)), sdl2::render::RendererFlip::None)
     }
 
     fn map(&self, map: &Map, pos: &Vec2) -> SdlResult<()> {
         let background_texture = self.textures.get(&map.background_texture).unwrap();
 
         // Fill the whole screen with the background color
-        try!(self.renderer.set_draw_color(map.background_color.to_sdl_color()));
+        try!(self.renderer.set_draw_color(sdl_color(map.background_color)));
         let rect = sdl2::rect::Rect {
             x: 0, y: 0, w: SCREEN_WIDTH as i32, h: SCREEN_HEIGHT as i32
         };
@@ -119,15 +143,18 @@ impl RenderEnv {
         try!(self.renderer.set_draw_color(sdl2::pixels::Color::RGB(0xFF, 0x00, 0x00)));
         for rect in bbox.rects.iter() {
             let (tl, tr, bl, br) = rect.transform(trans);
-            try!(self.renderer.draw_line(tl.point(), tr.point()));
-            try!(self.renderer.draw_line(tr.point(), br.point()));
-            try!(self.renderer.draw_line(br.point(), bl.point()));
-            try!(self.renderer.draw_line(bl.point(), tl.point()));
+            try!(self.renderer.draw_line(sdl_point(tl), sdl_point(tr)));
+            try!(self.renderer.draw_line(sdl_point(tr), sdl_point(br)));
+            try!(self.renderer.draw_line(sdl_point(br), sdl_point(bl)));
+            try!(self.renderer.draw_line(sdl_point(bl), sdl_point(tl)));
         };
         Ok(())
     }
 
     fn ship(&self, ship: &Ship, sspec: &GameSpec, trans: &Transform) -> SdlResult<()> {
+        // Dead ships are not drawn at all
+        if !ship.alive() { return Ok(()) };
+
         let trans = trans.adjust(&ship.trans);
         let spec = sspec.get_spec(ship.spec).is_ship();
 
@@ -146,7 +173,10 @@ impl RenderEnv {
 
     fn shooter(&self, shooter: &Shooter, sspec: &GameSpec, trans: &Transform) -> SdlResult<()> {
         let spec = sspec.get_spec(shooter.spec).is_shooter();
-        self.sprite(&spec.sprite, &trans.adjust(&spec.trans))
+        let trans = trans.adjust(&shooter.trans);
+        try!(self.sprite(&spec.sprite, &trans));
+        // Debugging -- render bbox
+        self.bbox(&spec.bbox, &trans)
     }
 
     fn actors(&self, actors: &Actors, spec: &GameSpec, trans: &Transform) -> SdlResult<()> {
@@ -157,14 +187,101 @@ impl RenderEnv {
         Ok(())
     }
 
+    // Shown to a dead player: a bar at the bottom of the screen
+    // emptying until the respawn.
+    fn dead(&self, ship: &Ship, sspec: &GameSpec) -> SdlResult<()> {
+        let spec = sspec.get_spec(ship.spec).is_ship();
+        let dead_for = ship.dead_for.unwrap_or(0.);
+        let left = 1. - dead_for / spec.respawn_delay;
+        let w = SCREEN_WIDTH / 4.;
+        let h = 10.;
+        let bar = Rect{
+            pos: Vec2{x: (SCREEN_WIDTH - w) / 2., y: SCREEN_HEIGHT - 4.*h},
+            w: w,
+            h: h,
+        };
+        try!(self.renderer.set_draw_color(sdl2::pixels::Color::RGB(0x30, 0x30, 0x30)));
+        try!(self.renderer.fill_rect(&sdl_rect(&bar)));
+        try!(self.renderer.set_draw_color(sdl2::pixels::Color::RGB(0xFF, 0x00, 0x00)));
+        try!(self.renderer.fill_rect(&sdl_rect(&Rect{w: w * left, ..bar})));
+        Ok(())
+    }
+
     pub fn game(&self, game: &Game, spec: &GameSpec, player: ActorId) -> SdlResult<()> {
-        let trans = &game.actors.get(player).unwrap().is_ship().camera.transform();
+        // The player might have been removed by the server already, in
+        // which case we just show the map.
+        let ship = game.actors.get(player).map(|actor| actor.is_ship());
+        let trans = &match ship {
+            None       => Transform::id(),
+            Some(ship) => ship.camera.transform(),
+        };
         try!(self.map(&spec.map, &trans.pos));
         try!(self.actors(&game.actors, spec, trans));
-        Ok(())
+        match ship {
+            Some(ship) if !ship.alive() => self.dead(ship, spec),
+            _                           => Ok(()),
+        }
     }
 
     pub fn player_game(&self, game: &PlayerGame, spec: &GameSpec) -> SdlResult<()> {
         self.game(game.game.deref(), spec, game.player)
     }
+
+    // Writes `text` with its top-left corner at `pos`, each pixel of
+    // the font being `scale` pixels wide.
+    pub fn text(&self, text: &str, pos: Vec2, scale: f32, color: Color) -> SdlResult<()> {
+        try!(self.renderer.set_draw_color(sdl_color(color)));
+        let mut x = pos.x;
+        for c in text.chars() {
+            let glyph = font::glyph(c);
+            for row in range(0, font::GLYPH_H) {
+                for col in range(0, font::GLYPH_W) {
+                    if glyph[row] & (1 << (font::GLYPH_W - 1 - col)) != 0 {
+                        let pixel = Rect{
+                            pos: Vec2{x: x + (col as f32)*scale, y: pos.y + (row as f32)*scale},
+                            w: scale,
+                            h: scale,
+                        };
+                        try!(self.renderer.fill_rect(&sdl_rect(&pixel)));
+                    }
+                }
+            };
+            x += ((font::GLYPH_W + 1) as f32) * scale;
+        };
+        Ok(())
+    }
+
+    // The recent chat lines go in the top-left corner, what the player
+    // is typing in the bottom-left one.
+    pub fn chat(&self, lines: &[String], typing: Option<&str>) -> SdlResult<()> {
+        let scale = 2.;
+        let line_h = ((font::GLYPH_H + 2) as f32) * scale;
+        let color = Color(0xFF, 0xFF, 0xFF);
+        for (i, line) in lines.iter().enumerate() {
+            let pos = Vec2{x: line_h, y: line_h * ((i + 1) as f32)};
+            try!(self.text(line.as_slice(), pos, scale, color));
+        };
+        match typing {
+            None         => Ok(()),
+            Some(typing) => {
+                let pos = Vec2{x: line_h, y: SCREEN_HEIGHT - 2.*line_h};
+                self.text(format!("> {}_", typing).as_slice(), pos, scale, color)
+            },
+        }
+    }
+
+    // The network stats go in the top-right corner, in yellow so that
+    // they don't get mixed up with the chat.
+    pub fn stats(&self, lines: &[String]) -> SdlResult<()> {
+        let scale = 2.;
+        let line_h = ((font::GLYPH_H + 2) as f32) * scale;
+        let char_w = ((font::GLYPH_W + 1) as f32) * scale;
+        let color = Color(0xFF, 0xFF, 0x00);
+        let width = lines.iter().map(|line| line.len()).max().unwrap_or(0) as f32 * char_w;
+        for (i, line) in lines.iter().enumerate() {
+            let pos = Vec2{x: SCREEN_WIDTH - width - line_h, y: line_h * ((i + 1) as f32)};
+            try!(self.text(line.as_slice(), pos, scale, color));
+        };
+        Ok(())
+    }
 }
@@ -1,5 +1,5 @@
 #![allow(unstable)]
-extern crate sdl2;
+extern crate "rustc-serialize" as rustc_serialize;
 #[macro_use] extern crate log;
 
 extern crate actors;
@@ -8,87 +8,282 @@ extern crate conf;
 extern crate input;
 extern crate ai;
 extern crate network;
+extern crate geometry;
+extern crate clock;
 
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::cmp::min;
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 use std::collections::RingBuf;
-use std::thread::Thread;
+use std::collections::hash_map::Entry;
 use std::ops::Deref;
 use std::io::IoErrorKind;
+use std::num::Float;
 
 use actors::*;
 use specs::*;
 use conf::*;
 use input::*;
 use ai::*;
+use geometry::*;
+use clock::Clock;
+#[cfg(test)] use std::thread::Thread;
+#[cfg(test)] use std::io::net::ip::ToSocketAddr;
+
+// ---------------------------------------------------------------------
+// Messages
+
+#[derive(PartialEq, Clone, Show, RustcEncodable, RustcDecodable)]
+pub struct ChatLine {
+    pub from: String,
+    pub text: String,
+}
+
+// Clients can send us anything as names and chat lines, so we keep the
+// printable characters, up to `MAX_TEXT_LEN`.  `None` if nothing is
+// left.
+fn clean_text(text: &str) -> Option<String> {
+    let printable: String = text.chars()
+        .filter(|c| *c >= ' ' && *c <= '~')
+        .take(MAX_TEXT_LEN)
+        .collect();
+    let trimmed = printable.trim();
+    if trimmed.is_empty() { None } else { Some(trimmed.to_string()) }
+}
+
+/// What clients send to the server
+#[derive(PartialEq, Clone, Show, RustcEncodable, RustcDecodable)]
+pub enum ClientMsg {
+    /// The first message, with the name the player wants to use
+    Join(Option<String>),
+    /// Numbered, so that the server can tell us which inputs it applied,
+    /// and with the tick they're meant for
+    Input(u32, u32, Input),
+    Chat(String),
+    /// Sent by remote clients for each `Snapshot` they get
+    Ack(u32),
+}
+
+impl ClientMsg {
+    /// Inputs and acks are sent all the time, so losing one is no big
+    /// deal.
+    pub fn channel(&self) -> network::Channel {
+        match *self {
+            ClientMsg::Input(..) => network::Channel::Unreliable,
+            ClientMsg::Ack(_)    => network::Channel::Unreliable,
+            _                    => network::Channel::Reliable,
+        }
+    }
+}
+
+/// What the server sends to clients
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub enum ServerMsg {
+    /// With the last input of the player that went into the game
+    Game(PlayerGame, Option<u32>),
+    Chat(ChatLine),
+}
+
+/// A game as sent to remote clients: the difference with a game they
+/// told us they have, or with the empty game if there's none.
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct Snapshot {
+    pub id: u32,
+    pub base: Option<u32>,
+    pub player: ActorId,
+    pub delta: GameDelta,
+    pub last_input: Option<u32>,
+}
+
+/// What the server sends to remote clients
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub enum RemoteMsg {
+    Snapshot(Snapshot),
+    Chat(ChatLine),
+}
+
+impl RemoteMsg {
+    /// Same as for `ClientMsg`, the next snapshot will replace a lost
+    /// one.
+    pub fn channel(&self) -> network::Channel {
+        match *self {
+            RemoteMsg::Snapshot(_) => network::Channel::Unreliable,
+            RemoteMsg::Chat(_)     => network::Channel::Reliable,
+        }
+    }
+}
+
+// What the server sends to its clients internally -- the game is
+// shared, not copied for each player, and so are the last inputs
+// applied for each of them.
+#[derive(Clone)]
+enum Update {
+    Game(Arc<Game>, Arc<HashMap<ActorId, u32>>),
+    Chat(ChatLine),
+}
+
+// What clients send to the server internally
+enum Cmd {
+    Msg(ClientMsg),
+    Lag(f32),
+    Leave,
+}
 
 // ---------------------------------------------------------------------
 // Generic client handle and utilities
 
 pub trait ClientSend {
+    /// `false` if we should stop.  `seq` should go up with each input,
+    /// and `tick` is the one of the server's ticks the input is meant
+    /// for.
+    fn send_input(&mut self, seq: u32, tick: u32, input: Input) -> bool;
+
     /// `false` if we should stop.
-    fn send_input(&mut self, input: Input) -> bool;
+    fn send_chat(&mut self, text: String) -> bool;
+
+    /// How the connection to the server is doing, if there is one.
+    fn stats(&self) -> Option<network::Stats> {
+        None
+    }
 }
 
 pub trait ClientRecv {
     /// `None` if we should stop.
-    fn recv_game(&mut self) -> Option<PlayerGame>;
+    fn recv_msg(&mut self) -> Option<ServerMsg>;
 }
 
 pub fn attach_ai<A: Ai + ?Sized, S: ClientSend, R: ClientRecv, F: Fn(PlayerGame)>(send: &mut S, recv: &mut R, ai: &A, on_game_update: F) {
+    // The AI doesn't predict anything, so it doesn't care about which
+    // inputs got applied, and it wants them in as soon as possible.
+    let mut seq = 0;
     loop {
-        let _ = send.send_input(Input::new());
-        match recv.recv_game() {
+        let _ = send.send_input(seq, 0, Input::new());
+        seq += 1;
+        match recv.recv_msg() {
             None => break,
-            Some(player_game) => {
+            Some(ServerMsg::Chat(line)) => info!("{}: {}", line.from, line.text),
+            Some(ServerMsg::Game(player_game, _)) => {
                 let input = ai.move_(&player_game);
+                let tick = player_game.game.tick + 1;
                 on_game_update(player_game);
-                if !send.send_input(input) { break };
+                if !send.send_input(seq, tick, input) { break };
+                seq += 1;
             }
         }
     }
 }
 
-pub fn attach_sdl<S: ClientSend + Send + Clone, R: ClientRecv, F: Fn(PlayerGame)>(send: &S, recv: &mut R, on_game_update: F) {
-    let (quit_tx, quit_rx) = channel();
-    let mut worker_send = send.clone();
+// ---------------------------------------------------------------------
+// Prediction
 
-    // Thread sending inputs
-    let _ = Thread::spawn(move || {
-        // Send input every 5ms
-        let mut input = Input::new();
-        let _ = worker_send.send_input(input);
-        loop {
-            let new_input = input.process_events();
-            if new_input.quit {
-                let _ = quit_tx.send(());
-                break
-            }
-            if new_input != input {
-                input = new_input;
-                let alive = worker_send.send_input(input);
-                if !alive { break };
-            }
-            sdl2::timer::delay(5);
+/// Runs the player's ship ahead of the games we get from the server, by
+/// replaying on top of each the inputs the server hasn't applied yet.
+pub struct Predictor {
+    next_seq: u32,
+    /// Inputs not applied yet with when we sent them, oldest first
+    pending: RingBuf<(u32, Input, u32)>,
+    /// Where we last drew the ship, how fast it was going, and when
+    drawn: Option<(Vec2, Vec2, u32)>,
+    /// The tick of the last game we got, and when
+    server_tick: Option<(u32, u32)>,
+}
+
+impl Predictor {
+    pub fn new() -> Predictor {
+        Predictor{next_seq: 0, pending: RingBuf::new(), drawn: None, server_tick: None}
+    }
+
+    /// Records the tick of a game we got at `now`.
+    pub fn received(&mut self, tick: u32, now: u32) {
+        match self.server_tick {
+            Some((last, _)) if tick <= last => (),
+            _                               => self.server_tick = Some((tick, now)),
         }
-    });
+    }
 
-    // Get the game and draw
-    loop {
-        let quit = quit_rx.try_recv();
-        match quit {
-            Ok(()) => break,
-            Err(TryRecvError::Empty) => (),
-            Err(TryRecvError::Disconnected) => break,
+    /// Records an input sent at `now`, and gives back its seq and the
+    /// tick the server will be at when it gets it.  The last game we
+    /// got left the server about `rtt` milliseconds, a round trip,
+    /// before the input gets there.
+    pub fn input(&mut self, input: Input, now: u32, rtt: f32) -> (u32, u32) {
+        let tick = match self.server_tick {
+            // Whatever we ask for, the server applies it as soon as it can
+            None => 0,
+            Some((tick, at)) => tick + (((now - at) as f32 + rtt) / (TIME_STEP * 1000.)).ceil() as u32,
+        };
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.pending.push_back((seq, input, now));
+        // If the server stopped applying our inputs, there's no point
+        // in replaying all of them.
+        if self.pending.len() > MAX_PENDING_INPUTS {
+            let _ = self.pending.pop_front();
         }
+        (seq, tick)
+    }
 
-        match recv.recv_game() {
-            None => break,
-            Some(game) => on_game_update(game),
+    /// The game with our ship where we expect it to be at `now`.
+    /// `last_input` is the last of our inputs that went into the game.
+    pub fn predict(&mut self, spec: &GameSpec, player_game: &PlayerGame, last_input: Option<u32>, now: u32) -> PlayerGame {
+        match last_input {
+            None => (),
+            Some(last_input) => loop {
+                let front = self.pending.front().map(|&(seq, _, _)| seq);
+                match front {
+                    Some(seq) if seq <= last_input => { let _ = self.pending.pop_front(); },
+                    _ => break,
+                }
+            },
         };
-    };
+
+        let player = player_game.player;
+        let mut ship = match player_game.game.actors.get(player) {
+            Some(&Actor::Ship(ship)) if ship.alive() => ship,
+            _ => {
+                self.drawn = None;
+                return player_game.clone();
+            },
+        };
+
+        // Each input lasts until the next one was sent, in steps no
+        // longer than the server's.  Bullets fired on the way are left
+        // to the server.
+        let step = (TIME_STEP * 1000.) as u32;
+        let mut fired = Actors::prepare_new(&player_game.game.actors);
+        for (i, &(_, input, sent)) in self.pending.iter().enumerate() {
+            let until = match self.pending.get(i + 1) {
+                None                     => now,
+                Some(&(_, _, next_sent)) => next_sent,
+            };
+            let mut t = sent;
+            while t < until {
+                let dt = min(step, until - t);
+                ship = ship.advance(spec, &mut fired, player, Some(input), dt as f32 / 1000.).unwrap();
+                t += dt;
+            }
+        }
+
+        // Rather than jumping to the new prediction, we start from
+        // where the old one would be by now and let the error fade
+        // away over the next frames -- unless it's way off, like after
+        // a respawn.
+        let predicted = ship.trans.pos;
+        match self.drawn {
+            None => (),
+            Some((pos, vel, at)) => {
+                let error = pos + vel * ((now - at) as f32 / 1000.) - predicted;
+                if error.mag() <= MAX_CORRECTION {
+                    ship.trans.pos = spec.map.bound(predicted + error * CORRECTION_DECAY);
+                }
+            },
+        };
+        self.drawn = Some((ship.trans.pos, ship.vel, now));
+
+        let mut game = player_game.game.deref().clone();
+        game.actors.insert(player, Actor::Ship(ship));
+        PlayerGame{player: player, game: Arc::new(game)}
+    }
 }
 
 // ---------------------------------------------------------------------
@@ -96,16 +291,88 @@ pub fn attach_sdl<S: ClientSend + Send + Clone, R: ClientRecv, F: Fn(PlayerGame)
 
 const SERVER_GAMES: usize = 32;
 
+// The inputs of a player waiting for the tick they're meant for.  Each
+// input lasts until the next one, so when a player has nothing for a
+// tick they get their last one again.
+struct InputQueue {
+    /// Seq, tick and input, in order of tick and then of seq
+    queued: Vec<(u32, u32, Input)>,
+    /// The last one applied, with its seq
+    last: Option<(u32, Input)>,
+}
+
+impl InputQueue {
+    fn new() -> InputQueue {
+        InputQueue{queued: Vec::new(), last: None}
+    }
+
+    // `false` if the input is dropped: if we have it already, if a later
+    // one was applied already, or if it's meant for too far after
+    // `tick`.
+    fn push(&mut self, seq: u32, input_tick: u32, input: Input, tick: u32) -> bool {
+        let stale = match self.last {
+            None            => false,
+            Some((last, _)) => seq <= last,
+        };
+        let duplicate = self.queued.iter().any(|&(queued, _, _)| queued == seq);
+        if stale || duplicate || input_tick > tick + MAX_INPUT_AHEAD { return false };
+        let len = self.queued.len();
+        let pos = self.queued.iter()
+            .position(|&(queued_seq, queued_tick, _)| (queued_tick, queued_seq) > (input_tick, seq))
+            .unwrap_or(len);
+        self.queued.insert(pos, (seq, input_tick, input));
+        true
+    }
+
+    // The input for `tick`.  Inputs which are late go in straight away,
+    // and when more than one is due the one sent last wins.  `None` if
+    // the player never sent anything.
+    fn pop(&mut self, tick: u32) -> Option<Input> {
+        let due = self.queued.iter().take_while(|&&(_, input_tick, _)| input_tick <= tick).count();
+        let latest = self.queued.iter().take(due)
+            .max_by(|&&(seq, _, _)| seq)
+            .map(|&(seq, _, input)| (seq, input));
+        match latest {
+            None => (),
+            Some((seq, input)) => {
+                // Inputs sent before it are of no use anymore, even if
+                // they were meant for later
+                self.queued = self.queued.iter().skip(due)
+                    .filter(|&&(queued, _, _)| queued > seq)
+                    .map(|queued| *queued)
+                    .collect();
+                self.last = Some((seq, input));
+            },
+        };
+        self.last.map(|(_, input)| input)
+    }
+
+    fn last_seq(&self) -> Option<u32> {
+        self.last.map(|(seq, _)| seq)
+    }
+}
+
 pub struct Server {
     spec: Arc<GameSpec>,
     games: Arc<Mutex<RingBuf<Game>>>,
-    clients: Arc<Mutex<HashMap<ActorId, Sender<Arc<Game>>>>>,
-    cmds_tx: Sender<(ActorId, Input)>,
-    cmds_rx: Receiver<(ActorId, Input)>,
+    clients: Arc<Mutex<HashMap<ActorId, Sender<Update>>>>,
+    names: Arc<Mutex<HashMap<ActorId, String>>>,
+    // The inputs each player sent, see `InputQueue`
+    inputs: Mutex<HashMap<ActorId, InputQueue>>,
+    // How far behind each player sees the game, in seconds
+    lags: Mutex<HashMap<ActorId, f32>>,
+    cmds_tx: Sender<(ActorId, Cmd)>,
+    cmds_rx: Receiver<(ActorId, Cmd)>,
+    clock: Clock,
 }
 
 impl Server {
     pub fn new(spec: Arc<GameSpec>, game: Game) -> Server {
+        Server::with_clock(spec, game, Clock::real())
+    }
+
+    /// Like `new`, with the ticks going by according to `clock`.
+    pub fn with_clock(spec: Arc<GameSpec>, game: Game, clock: Clock) -> Server {
         let (cmds_tx, cmds_rx) = channel();
         let mut games = RingBuf::with_capacity(SERVER_GAMES);
         games.push_front(game);
@@ -113,8 +380,12 @@ impl Server {
             spec: spec,
             games: Arc::new(Mutex::new(games)),
             clients: Arc::new(Mutex::new(HashMap::new())),
+            names: Arc::new(Mutex::new(HashMap::new())),
+            inputs: Mutex::new(HashMap::new()),
+            lags: Mutex::new(HashMap::new()),
             cmds_tx: cmds_tx,
             cmds_rx: cmds_rx,
+            clock: clock,
         }
     }
 
@@ -123,11 +394,20 @@ impl Server {
             spec: self.spec.clone(),
             games: self.games.clone(),
             clients: self.clients.clone(),
+            names: self.names.clone(),
             cmds_tx: self.cmds_tx.clone(),
         }
     }
 
-    fn remove_player(&self, player: ActorId) {
+    fn name(&self, player: ActorId) -> String {
+        let names = self.names.lock().unwrap();
+        match names.get(&player) {
+            None       => format!("Player {}", player),
+            Some(name) => name.clone(),
+        }
+    }
+
+    fn remove_player(&self, player: ActorId, why: &str) {
         // They might be both have been removed already
         {
             let mut clients = self.clients.lock().unwrap();
@@ -136,70 +416,118 @@ impl Server {
         {
             let mut games = self.games.lock().unwrap();
             let mut game = games.front_mut().unwrap();
-            let _ = game.actors.remove(player);
+            game.remove_player(player);
         };
-        info!("Player {} left the game -- disconnected when sending", player);
+        info!("Player {} ({}) left the game -- {}", player, self.name(player), why);
+        let _ = self.names.lock().unwrap().remove(&player);
+        let _ = self.inputs.lock().unwrap().remove(&player);
+        let _ = self.lags.lock().unwrap().remove(&player);
     }
     
-    fn broadcast(&self, game: Arc<Game>) {
+    fn broadcast(&self, update: Update) {
         // When a client is disconnected, clean it up
         let mut dead: Vec<ActorId> = Vec::new();
         {
             // Lock clients
             let clients = self.clients.lock().unwrap();
             for (actor_id, tx) in clients.iter()  {
-                let mb_err = tx.send(game.clone());
+                let mb_err = tx.send(update.clone());
                 if mb_err.is_err() {
                     dead.push(*actor_id);
                 } else {
-                    debug!("Game sent to {}", actor_id);
+                    debug!("Update sent to {}", actor_id);
                 }
             };
             // Unlock clients
         }
         for actor_id in dead.iter() {
-            self.remove_player(*actor_id);
+            self.remove_player(*actor_id, "disconnected when sending");
         }
     }
 
-    fn prepare_inputs(&self) -> Option<Vec<PlayerInput>> {
-        let mut cmds: Vec<PlayerInput> = Vec::new();
+    // The inputs for `tick`, see `InputQueue`.  Chat messages are
+    // broadcast straight away.
+    fn prepare_inputs(&self, tick: u32) -> Option<Vec<PlayerInput>> {
         loop {
             match self.cmds_rx.try_recv() {
-                Ok((player, x)) => {
-                    debug!("Got input from player {}", player);
-                    cmds.push(PlayerInput{
-                        player: player,
-                        input: x,
-                    })
+                Ok((player, Cmd::Msg(ClientMsg::Input(seq, input_tick, x)))) => {
+                    debug!("Got input {} for tick {} from player {}", seq, input_tick, player);
+                    let mut inputs = self.inputs.lock().unwrap();
+                    let queue = match inputs.entry(player) {
+                        Entry::Vacant(entry)   => entry.insert(InputQueue::new()),
+                        Entry::Occupied(entry) => entry.into_mut(),
+                    };
+                    if !queue.push(seq, input_tick, x, tick) {
+                        debug!("Input {} for tick {} from player {} is stale or too far ahead, dropping", seq, input_tick, player);
+                    }
+                },
+                Ok((player, Cmd::Msg(ClientMsg::Chat(text)))) => match clean_text(text.as_slice()) {
+                    None =>
+                        debug!("Got empty chat line from player {}, dropping", player),
+                    Some(text) => {
+                        let line = ChatLine{from: self.name(player), text: text};
+                        info!("{}: {}", line.from, line.text);
+                        self.broadcast(Update::Chat(line));
+                    },
+                },
+                Ok((player, Cmd::Msg(ClientMsg::Join(_)))) =>
+                    warn!("Player {} tried to join twice, ignoring", player),
+                Ok((player, Cmd::Msg(ClientMsg::Ack(_)))) =>
+                    debug!("Got snapshot ack from player {}, ignoring", player),
+                Ok((player, Cmd::Lag(lag))) => {
+                    let _ = self.lags.lock().unwrap().insert(player, lag);
+                },
+                Ok((player, Cmd::Leave)) =>
+                    self.remove_player(player, "quit"),
+                Err(TryRecvError::Empty) => {
+                    let mut inputs = self.inputs.lock().unwrap();
+                    return Some(inputs.iter_mut()
+                        .filter_map(|(player, queue)| queue.pop(tick).map(|input| PlayerInput{player: *player, input: input}))
+                        .collect());
                 },
-                Err(TryRecvError::Empty) => return Some(cmds),
                 Err(TryRecvError::Disconnected) => return None,
             }
         }
     }
 
+    // How many ticks back each player's bullets are checked, see
+    // `LagHits`.  We don't go further than `MAX_REWIND`, so that
+    // players can't dodge behind cover for too long.
+    fn rewind_ticks(&self) -> HashMap<ActorId, usize> {
+        let max_ticks = (MAX_REWIND / TIME_STEP) as usize;
+        self.lags.lock().unwrap().iter()
+            .map(|(player, lag)| (*player, min((*lag / TIME_STEP).round() as usize, max_ticks)))
+            .collect()
+    }
+
     pub fn run(&self) {
-        let wait_ms = (TIME_STEP * 1000.) as usize;
+        let tick_ms = (TIME_STEP * 1000.) as u32;
 
         loop {
-            let time_begin = sdl2::get_ticks() as usize;
+            let time_begin = self.clock.ticks();
 
-            match self.prepare_inputs() {
+            let tick = self.games.lock().unwrap().front().unwrap().tick + 1;
+            match self.prepare_inputs(tick) {
                 None => break,
                 Some(inputs) => {
                     let game = {
                         let mut games = self.games.lock().unwrap();
-                        let new_game = games.front().unwrap().advance(self.spec.deref(), &inputs, TIME_STEP);
+                        let ticks = self.rewind_ticks();
+                        let new_game = {
+                            let lag = Lag{history: games.deref(), ticks: &ticks};
+                            games.front().unwrap().advance_lagged(self.spec.deref(), &inputs, &lag, TIME_STEP)
+                        };
                         if games.len() >= SERVER_GAMES {
                             games.pop_back().unwrap();
                         };
                         games.push_front(new_game.clone());
                         new_game
                     };
-                    self.broadcast(Arc::new(game));
-                    let time_end = sdl2::get_ticks() as usize;
-                    sdl2::timer::delay(wait_ms - min(wait_ms, time_end - time_begin));
+                    let last_inputs = self.inputs.lock().unwrap().iter()
+                        .filter_map(|(player, queue)| queue.last_seq().map(|seq| (*player, seq)))
+                        .collect();
+                    self.broadcast(Update::Game(Arc::new(game), Arc::new(last_inputs)));
+                    self.clock.delay_until(time_begin + tick_ms);
                 },
             }
         }
@@ -211,23 +539,28 @@ impl Server {
 pub struct JoinHandle {
     spec: Arc<GameSpec>,
     games: Arc<Mutex<RingBuf<Game>>>,
-    clients: Arc<Mutex<HashMap<ActorId, Sender<Arc<Game>>>>>,
-    cmds_tx: Sender<(ActorId, Input)>,
+    clients: Arc<Mutex<HashMap<ActorId, Sender<Update>>>>,
+    names: Arc<Mutex<HashMap<ActorId, String>>>,
+    cmds_tx: Sender<(ActorId, Cmd)>,
 }
 
 impl JoinHandle {
-    pub fn join(&self) -> (ActorId, ServerClientSend, ServerClientRecv) {
+    /// Players without a name, or whose name has nothing printable in
+    /// it, get one based on their `ActorId`.
+    pub fn join(&self, name: Option<String>) -> (ActorId, ServerClientSend, ServerClientRecv) {
         let player = {
             let mut games = self.games.lock().unwrap();
             games.front_mut().unwrap().add_ship(self.spec.deref())
         };
+        let name = name.and_then(|name| clean_text(name.as_slice())).unwrap_or(format!("Player {}", player));
+        let _ = self.names.lock().unwrap().insert(player, name.clone());
         let rx = {
             let mut clients = self.clients.lock().unwrap();
             let (tx, rx) = channel();
             clients.insert(player, tx);
             rx
         };
-        info!("Player {} joined.", player);
+        info!("Player {} ({}) joined.", player, name);
         (player,
          ServerClientSend{player: player, sender: self.cmds_tx.clone()},
          ServerClientRecv{player: player, receiver: rx})
@@ -240,31 +573,55 @@ impl JoinHandle {
 #[derive(Clone)]
 pub struct ServerClientSend {
     player: ActorId,
-    sender: Sender<(ActorId, Input)>,
+    sender: Sender<(ActorId, Cmd)>,
 }
 
 impl ClientSend for ServerClientSend {
-    fn send_input(&mut self, input: Input) -> bool {
-        let send_res = self.sender.send((self.player, input));
+    fn send_input(&mut self, seq: u32, tick: u32, input: Input) -> bool {
+        let send_res = self.sender.send((self.player, Cmd::Msg(ClientMsg::Input(seq, tick, input))));
+        if send_res.is_err() { return false };
+        true
+    }
+
+    fn send_chat(&mut self, text: String) -> bool {
+        let send_res = self.sender.send((self.player, Cmd::Msg(ClientMsg::Chat(text))));
         if send_res.is_err() { return false };
         true
     }
 }
 
+impl ServerClientSend {
+    /// How far behind the server the player sees the game, in seconds,
+    /// so that their bullets hit what they aimed at.  Players are
+    /// assumed not to be behind until this is called.
+    pub fn set_lag(&mut self, lag: f32) -> bool {
+        let send_res = self.sender.send((self.player, Cmd::Lag(lag)));
+        if send_res.is_err() { return false };
+        true
+    }
+
+    /// Removes the player from the game straight away, rather than when
+    /// the server next fails to send them something.
+    pub fn leave(self) {
+        let _ = self.sender.send((self.player, Cmd::Leave));
+    }
+}
+
 pub struct ServerClientRecv {
     player: ActorId,
-    receiver: Receiver<Arc<Game>>,
+    receiver: Receiver<Update>,
 }
 
 impl ClientRecv for ServerClientRecv {
-    fn recv_game(&mut self) -> Option<PlayerGame> {
+    fn recv_msg(&mut self) -> Option<ServerMsg> {
         let recv_res = self.receiver.recv();
         match recv_res {
             Err(_) => None,
-            Ok(game) => Some(PlayerGame{
+            Ok(Update::Game(game, last_inputs)) => Some(ServerMsg::Game(PlayerGame{
                 player: self.player,
                 game: game
-            }),
+            }, last_inputs.get(&self.player).map(|seq| *seq))),
+            Ok(Update::Chat(line)) => Some(ServerMsg::Chat(line)),
         }
     }
 }
@@ -272,34 +629,421 @@ impl ClientRecv for ServerClientRecv {
 // ---------------------------------------------------------------------
 // Network `ClientHandle`
 
+fn send_msg(handle: &mut network::ClientHandle, msg: &ClientMsg) -> bool {
+    loop {
+        let send_res = handle.send(msg, msg.channel());
+        match send_res {
+            Err(err) => match err.kind {
+                IoErrorKind::Closed => return false,
+                _ => warn!("Got unexpected error {}, continuing", err),
+            },
+            Ok(()) => return true,
+        }
+    }
+}
+
 impl ClientSend for network::ClientHandle {
-    fn send_input(&mut self, input: Input) -> bool {
+    fn send_input(&mut self, seq: u32, tick: u32, input: Input) -> bool {
+        send_msg(self, &ClientMsg::Input(seq, tick, input))
+    }
+
+    fn send_chat(&mut self, text: String) -> bool {
+        send_msg(self, &ClientMsg::Chat(text))
+    }
+
+    fn stats(&self) -> Option<network::Stats> {
+        Some(network::ClientHandle::stats(self))
+    }
+}
+
+// ---------------------------------------------------------------------
+// Snapshots
+
+/// Turns the games for a remote client into snapshots, against the
+/// most recent game they acked.
+pub struct SnapshotEncoder {
+    next_id: u32,
+    /// What we sent after `acked`, oldest first
+    sent: RingBuf<(u32, Arc<Game>)>,
+    acked: Option<(u32, Arc<Game>)>,
+}
+
+impl SnapshotEncoder {
+    pub fn new() -> SnapshotEncoder {
+        SnapshotEncoder{next_id: 0, sent: RingBuf::new(), acked: None}
+    }
+
+    fn snapshot(&mut self, player_game: &PlayerGame, last_input: Option<u32>) -> Snapshot {
+        let id = self.next_id;
+        self.next_id += 1;
+        // The client only keeps so many games around
+        let (base, delta) = match self.acked {
+            Some((base_id, ref base)) if id - base_id <= SNAPSHOT_HISTORY as u32 =>
+                (Some(base_id), GameDelta::new(base.deref(), player_game.game.deref())),
+            _ =>
+                (None, GameDelta::new(&Game::empty(), player_game.game.deref())),
+        };
+        self.sent.push_back((id, player_game.game.clone()));
+        if self.sent.len() > SNAPSHOT_HISTORY {
+            let _ = self.sent.pop_front();
+        }
+        Snapshot{id: id, base: base, player: player_game.player, delta: delta, last_input: last_input}
+    }
+
+    pub fn encode(&mut self, msg: ServerMsg) -> RemoteMsg {
+        match msg {
+            ServerMsg::Game(player_game, last_input) => RemoteMsg::Snapshot(self.snapshot(&player_game, last_input)),
+            ServerMsg::Chat(line)                    => RemoteMsg::Chat(line),
+        }
+    }
+
+    /// How much older the last acked game is than the last one sent, in
+    /// seconds -- about a round trip, as clients ack straight away.
+    pub fn lag(&self) -> Option<f32> {
+        match self.acked {
+            None                 => None,
+            Some((_, ref acked)) => Some(match self.sent.back() {
+                None                => 0.,
+                Some(&(_, ref last)) => last.time - acked.time,
+            }),
+        }
+    }
+
+    /// Old and unknown acks are ignored.
+    pub fn ack(&mut self, id: u32) {
+        if !self.sent.iter().any(|&(sent_id, _)| sent_id == id) { return };
         loop {
-            let send_res = self.send(&input);
-            match send_res {
-                Err(err) => match err.kind {
-                    IoErrorKind::Closed => return false,
-                    _ => warn!("Got unexpected error {}, continuing", err),
+            match self.sent.pop_front() {
+                None => break,
+                Some((sent_id, game)) => if sent_id == id {
+                    self.acked = Some((id, game));
+                    break;
                 },
-                Ok(()) => return true,
             }
         }
     }
 }
 
-impl ClientRecv for network::ClientHandle {
-    fn recv_game(&mut self) -> Option<PlayerGame> {
+/// Rebuilds the games from the snapshots, on the remote client.
+pub struct SnapshotDecoder {
+    /// Most recent last
+    history: RingBuf<(u32, Arc<Game>)>,
+}
+
+impl SnapshotDecoder {
+    pub fn new() -> SnapshotDecoder {
+        SnapshotDecoder{history: RingBuf::new()}
+    }
+
+    /// `None` if the snapshot is older than the last one, or we don't
+    /// have its base.
+    pub fn decode(&mut self, snapshot: Snapshot) -> Option<PlayerGame> {
+        match self.history.back() {
+            Some(&(last_id, _)) if snapshot.id <= last_id => {
+                debug!("Got snapshot {} after {}, dropping", snapshot.id, last_id);
+                return None;
+            },
+            _ => (),
+        };
+        let mb_game = match snapshot.base {
+            None => snapshot.delta.apply(&Game::empty()),
+            Some(base_id) => match self.history.iter().find(|&&(id, _)| id == base_id) {
+                None                 => None,
+                Some(&(_, ref base)) => snapshot.delta.apply(base.deref()),
+            },
+        };
+        match mb_game {
+            None => {
+                warn!("Got snapshot {} against unknown game {:?}, dropping", snapshot.id, snapshot.base);
+                None
+            },
+            Some(game) => {
+                let game = Arc::new(game);
+                self.history.push_back((snapshot.id, game.clone()));
+                if self.history.len() > SNAPSHOT_HISTORY {
+                    let _ = self.history.pop_front();
+                }
+                Some(PlayerGame{player: snapshot.player, game: game})
+            },
+        }
+    }
+}
+
+/// The receiving end of a remote client.
+pub struct RemoteRecv {
+    handle: network::ClientHandle,
+    snapshots: SnapshotDecoder,
+}
+
+impl RemoteRecv {
+    pub fn new(handle: network::ClientHandle) -> RemoteRecv {
+        RemoteRecv{handle: handle, snapshots: SnapshotDecoder::new()}
+    }
+}
+
+impl ClientRecv for RemoteRecv {
+    fn recv_msg(&mut self) -> Option<ServerMsg> {
         loop {
-            self.set_timeout(Some(5));
-            let recv_res = self.recv();
+            self.handle.set_timeout(Some(5));
+            let recv_res = self.handle.recv();
             match recv_res {
                 Err(err) => match err.kind {
                     IoErrorKind::Closed => return None,
                     IoErrorKind::TimedOut => (),
                     _ => warn!("Got unexpected error {}, continuing", err),
                 },
-                Ok(game) => return Some(game),
+                Ok(RemoteMsg::Chat(line)) => return Some(ServerMsg::Chat(line)),
+                Ok(RemoteMsg::Snapshot(snapshot)) => {
+                    let id = snapshot.id;
+                    let last_input = snapshot.last_input;
+                    match self.snapshots.decode(snapshot) {
+                        None => (),
+                        Some(player_game) => {
+                            // So that the server sends deltas against it
+                            if !send_msg(&mut self.handle, &ClientMsg::Ack(id)) { return None };
+                            return Some(ServerMsg::Game(player_game, last_input));
+                        },
+                    }
+                },
             }
         }
     }
 }
+
+// ---------------------------------------------------------------------
+// Tests
+
+#[cfg(test)]
+fn test_spec() -> GameSpec {
+    let mut textures = HashMap::new();
+    let _ = textures.insert("planes".to_string(), 0);
+    let _ = textures.insert("background".to_string(), 1);
+    GameSpec::from_json(include_str!("../assets/spec.json"), &textures).ok().unwrap()
+}
+
+#[test]
+fn test_send_big_game() {
+    let mut actors = Actors::new();
+    // Enough bullets for the game to go in more packets than a header
+    // can ack
+    for i in range(0, 1500us) {
+        let _ = actors.add(Actor::Bullet(Bullet{
+            spec: 0,
+            owner: 0,
+            trans: Transform{pos: Vec2{x: i as f32, y: 0.}, rotation: 0.},
+            vel: Vec2{x: 1., y: 0.},
+            age: 0.,
+        }));
+    }
+    let game = Game{actors: actors, scores: Scores::new(), tick: 0, time: 0.};
+
+    let server_addr = "127.0.0.1:10100".to_socket_addr().ok().unwrap();
+    let client_addr = "127.0.0.1:10101".to_socket_addr().ok().unwrap();
+    let mut server = network::Server::new(server_addr, MAX_CLIENTS).ok().unwrap();
+    let mut server_recv = server.clone();
+    let guard = Thread::scoped(move || { let _: network::Event<ClientMsg> = server_recv.recv().ok().unwrap(); });
+    let client = network::Client::new(server_addr, client_addr, false).ok().unwrap();
+    let _ = guard.join();
+    let mut client_recv = RemoteRecv::new(client.handle());
+
+    let mut encoder = SnapshotEncoder::new();
+    let msg = encoder.encode(ServerMsg::Game(PlayerGame{player: 0, game: Arc::new(game.clone())}, None));
+    server.send(client_addr, &msg, msg.channel()).ok().unwrap();
+    match client_recv.recv_msg() {
+        Some(ServerMsg::Game(player_game, _)) => assert!(*player_game.game == game),
+        _ => panic!("Expected a game"),
+    }
+
+    // And the same reliably
+    let msg = encoder.encode(ServerMsg::Game(PlayerGame{player: 0, game: Arc::new(game.clone())}, None));
+    server.send(client_addr, &msg, network::Channel::Reliable).ok().unwrap();
+    match client_recv.recv_msg() {
+        Some(ServerMsg::Game(player_game, _)) => assert!(*player_game.game == game),
+        _ => panic!("Expected a game"),
+    }
+}
+
+#[cfg(test)]
+fn snapshot(encoder: &mut SnapshotEncoder, game: &Game) -> Snapshot {
+    match encoder.encode(ServerMsg::Game(PlayerGame{player: 0, game: Arc::new(game.clone())}, None)) {
+        RemoteMsg::Snapshot(snapshot) => snapshot,
+        _                             => panic!("Expected a snapshot"),
+    }
+}
+
+#[test]
+fn test_snapshots() {
+    let mut game = Game::empty();
+    let bullet = Bullet{
+        spec: 0,
+        owner: 0,
+        trans: Transform{pos: Vec2{x: 0., y: 0.}, rotation: 0.},
+        vel: Vec2{x: 1., y: 0.},
+        age: 0.,
+    };
+    for _ in range(0, 10us) {
+        let _ = game.actors.add(Actor::Bullet(bullet));
+    }
+    let mut encoder = SnapshotEncoder::new();
+    let mut decoder = SnapshotDecoder::new();
+
+    // Nothing acked yet, so we get the whole game
+    let first = snapshot(&mut encoder, &game);
+    assert!(first.base.is_none());
+    assert!(*decoder.decode(first.clone()).unwrap().game == game);
+
+    // Lost on the way
+    game.time = 1.;
+    let _ = snapshot(&mut encoder, &game);
+
+    encoder.ack(first.id);
+    assert!(encoder.lag() == Some(1.));
+    game.time = 2.;
+    let third = snapshot(&mut encoder, &game);
+    assert!(third.base == Some(first.id));
+    assert!(*decoder.decode(third.clone()).unwrap().game == game);
+
+    // Late
+    let mut late = third.clone();
+    late.id = 1;
+    assert!(decoder.decode(late).is_none());
+
+    // Against a game the decoder never got
+    let mut unknown = third.clone();
+    unknown.id = 3;
+    unknown.base = Some(1);
+    assert!(decoder.decode(unknown).is_none());
+}
+
+#[test]
+fn test_predictor() {
+    let spec = test_spec();
+    let mut game = Game::empty();
+    let player = game.add_ship(&spec);
+    let player_game = PlayerGame{player: player, game: Arc::new(game)};
+    let ship = *player_game.game.actors.get(player).unwrap().is_ship();
+    let accel = Input{accel: true, ..Input::new()};
+
+    let mut predictor = Predictor::new();
+    let (first, _) = predictor.input(accel, 0, 0.);
+    let (second, _) = predictor.input(accel, 50, 0.);
+
+    // Nothing applied yet, so the ship is ahead of the server's
+    let predicted = predictor.predict(&spec, &player_game, None, 100);
+    let predicted_ship = *predicted.game.actors.get(player).unwrap().is_ship();
+    assert!(predicted_ship.trans.pos != ship.trans.pos);
+
+    // Applied inputs are forgotten
+    let _ = predictor.predict(&spec, &player_game, Some(first), 100);
+    assert!(predictor.pending.len() == 1);
+
+    // With nothing left to replay the ship heads back to the server's,
+    // but doesn't jump there
+    let corrected = predictor.predict(&spec, &player_game, Some(second), 100);
+    assert!(predictor.pending.is_empty());
+    let corrected_ship = *corrected.game.actors.get(player).unwrap().is_ship();
+    let before = (predicted_ship.trans.pos - ship.trans.pos).mag();
+    let after = (corrected_ship.trans.pos - ship.trans.pos).mag();
+    assert!(after > 0. && after < before);
+}
+
+#[test]
+fn test_predictor_ticks() {
+    let mut predictor = Predictor::new();
+    // Server's tick 10 got here at 1000ms.  Half a tick later with a two
+    // ticks round trip we should aim three ticks ahead.
+    predictor.received(10, 1000);
+    predictor.received(9, 1010);
+    let (_, tick) = predictor.input(Input::new(), 1025, 100.);
+    assert!(tick == 13);
+}
+
+#[test]
+fn test_input_queue() {
+    let left = Input{rotating: Rotating::Left, ..Input::new()};
+    let right = Input{rotating: Rotating::Right, ..Input::new()};
+    let accel = Input{accel: true, ..Input::new()};
+    let mut queue = InputQueue::new();
+    assert!(queue.pop(1).is_none());
+
+    // Early inputs wait for their tick
+    assert!(queue.push(0, 3, left, 1));
+    assert!(queue.pop(2).is_none());
+    assert!(queue.pop(3) == Some(left));
+    // Missing inputs get the last one again
+    assert!(queue.pop(4) == Some(left));
+
+    // Duplicates, and inputs older than the last applied, are dropped
+    assert!(!queue.push(0, 5, right, 4));
+    assert!(queue.push(2, 5, right, 4));
+    assert!(!queue.push(2, 5, right, 4));
+    // Late, and superseded by the one sent after
+    assert!(queue.push(1, 2, accel, 4));
+    assert!(queue.pop(5) == Some(right));
+    assert!(queue.last_seq() == Some(2));
+    assert!(queue.queued.is_empty());
+
+    // Late, so it goes in straight away
+    assert!(queue.push(3, 4, accel, 6));
+    assert!(queue.pop(6) == Some(accel));
+
+    // Way ahead
+    assert!(!queue.push(4, 7 + MAX_INPUT_AHEAD, left, 6));
+}
+
+#[test]
+fn test_clean_text() {
+    assert!(clean_text("  hello ") == Some("hello".to_string()));
+    assert!(clean_text("a\x07b\nc") == Some("abc".to_string()));
+    assert!(clean_text(" \t\x1b ").is_none());
+    assert!(clean_text("").is_none());
+    let long: String = range(0, MAX_TEXT_LEN * 2).map(|_| 'x').collect();
+    assert!(clean_text(long.as_slice()).unwrap().len() == MAX_TEXT_LEN);
+
+    // Names
+    let server = Server::new(Arc::new(test_spec()), Game::empty());
+    let (player, mut send, recv) = server.join_handle().join(Some(long.clone()));
+    assert!(server.name(player).len() == MAX_TEXT_LEN);
+    let (unnamed, _, _) = server.join_handle().join(Some("\n".to_string()));
+    assert!(server.name(unnamed) == format!("Player {}", unnamed));
+
+    // Chat lines
+    assert!(send.send_chat("".to_string()));
+    assert!(send.send_chat("\x01".to_string()));
+    assert!(send.send_chat(long.clone()));
+    assert!(server.prepare_inputs(1).is_some());
+    match recv.receiver.try_recv() {
+        Ok(Update::Chat(line)) => assert!(line.text.len() == MAX_TEXT_LEN),
+        _                      => panic!("Expected a chat line"),
+    };
+    assert!(recv.receiver.try_recv().is_err());
+}
+
+#[test]
+fn test_run() {
+    let spec = Arc::new(test_spec());
+    let clock = Clock::manual();
+    let server = Server::with_clock(spec, Game::empty(), clock.clone());
+    let (player, mut send, mut recv) = server.join_handle().join(None);
+    let _ = Thread::spawn(move || server.run());
+    let mut next_game = move || match recv.recv_msg() {
+        Some(ServerMsg::Game(player_game, last_input)) => (player_game, last_input),
+        _ => panic!("Expected a game"),
+    };
+
+    // The first tick goes straight away, and the next ones only when
+    // the clock gets there
+    let (first, _) = next_game();
+    assert!(first.game.tick == 1);
+    let accel = Input{accel: true, ..Input::new()};
+    assert!(send.send_input(0, 3, accel));
+    clock.advance(50);
+    let (second, last_input) = next_game();
+    assert!(second.game.tick == 2);
+    assert!(last_input.is_none());
+    clock.advance(50);
+    let (third, last_input) = next_game();
+    assert!(third.game.tick == 3);
+    assert!(last_input == Some(0));
+    assert!(third.game.actors.get(player).unwrap().is_ship().accel);
+}
@@ -1,10 +1,14 @@
-extern crate sdl2;
+#![allow(unstable)]
 extern crate "rustc-serialize" as rustc_serialize;
 
 extern crate geometry;
 
-use std::collections::HashMap;
-use sdl2::render::Texture;
+use std::collections::{HashMap, BTreeMap};
+use std::io::{File, IoError};
+use std::num::Float;
+use rustc_serialize::Decodable;
+use rustc_serialize::json;
+use rustc_serialize::json::Json;
 
 use geometry::*;
 
@@ -12,7 +16,6 @@ use geometry::*;
 // Textures
 
 pub type TextureId = u32;
-pub type Textures = HashMap<TextureId, Texture>;
 
 // ---------------------------------------------------------------------
 // Sprites
@@ -32,21 +35,26 @@ pub struct Sprite {
 #[derive(PartialEq, Clone, Copy, RustcEncodable, RustcDecodable)]
 pub struct Color(pub u8, pub u8, pub u8);
 
-impl Color {
-    pub fn to_sdl_color(self) -> sdl2::pixels::Color {
-        sdl2::pixels::Color::RGB(self.0, self.1, self.2)
-    }
-}
-
 // ---------------------------------------------------------------------
 // Map
 
+pub type TeamId = u32;
+
+// Something the map puts in the game when it starts
 #[derive(PartialEq, Clone, Copy, RustcEncodable, RustcDecodable)]
+pub struct Placement {
+    pub spec: SpecId,
+    pub trans: Transform,
+    pub team: Option<TeamId>,
+}
+
+#[derive(PartialEq, Clone, RustcEncodable, RustcDecodable)]
 pub struct Map {
     pub w: f32,
     pub h: f32,
     pub background_color: Color,
     pub background_texture: TextureId,
+    pub turrets: Vec<Placement>,
 }
 
 impl Map {
@@ -87,7 +95,7 @@ pub struct BBox {
 }
 
 impl BBox {
-    pub fn overlapping(this: BBox, this_t: &Transform, other: BBox, other_t: &Transform) -> bool {
+    pub fn overlapping(this: &BBox, this_t: &Transform, other: &BBox, other_t: &Transform) -> bool {
         let mut overlap = false;
         for this in this.rects.iter() {
             if overlap { break };
@@ -98,6 +106,36 @@ impl BBox {
         }
         overlap
     }
+
+    // The shortest side of the rects: however it's rotated, something
+    // going across the bbox is inside it for at least this long.
+    pub fn min_side(&self) -> f32 {
+        self.rects.iter().fold(std::f32::INFINITY, |side, rect| side.min(rect.w.min(rect.h)))
+    }
+
+    // A square centered on `pos` containing the bbox however it's
+    // rotated.
+    pub fn bounds(&self, pos: Vec2) -> Rect {
+        let mut radius_sq: f32 = 0.;
+        for rect in self.rects.iter() {
+            let corners = [
+                rect.pos,
+                rect.pos + Vec2{x: rect.w, y: 0.},
+                rect.pos + Vec2{x: 0., y: rect.h},
+                rect.pos + Vec2{x: rect.w, y: rect.h},
+            ];
+            for c in corners.iter() {
+                let d = c.x*c.x + c.y*c.y;
+                if d > radius_sq { radius_sq = d };
+            }
+        };
+        let radius = radius_sq.sqrt();
+        Rect{
+            pos: pos - Vec2{x: radius, y: radius},
+            w: 2. * radius,
+            h: 2. * radius,
+        }
+    }
 }
 
 // ---------------------------------------------------------------------
@@ -127,22 +165,46 @@ pub struct ShipSpec {
     pub firing_interval: f32,
     pub shoot_from: Vec2,
     pub bbox: BBox,
+    pub health: f32,
+    // How long a dead ship waits before respawning
+    pub respawn_delay: f32,
+    // The damage taken when crashing into another ship or a shooter
+    pub collision_damage: f32,
+    // How much of the speed is kept when bouncing off something, from
+    // 0 (none) to 1 (all of it)
+    pub restitution: f32,
 }
 
 #[derive(PartialEq, Clone, RustcEncodable, RustcDecodable)]
 pub struct BulletSpec {
     pub sprite: Sprite,
+    // The muzzle velocity, on top of the velocity of whoever fires it
     pub vel: f32,
+    pub gravity: f32,
     pub lifetime: f32,
+    // How much damage a ship takes when hit by this bullet
+    pub damage: f32,
     pub bbox: BBox,
 }
 
-#[derive(PartialEq, Clone, Copy, RustcEncodable, RustcDecodable)]
+#[derive(PartialEq, Clone, Copy, Show, RustcEncodable, RustcDecodable)]
+pub enum Aim {
+    // Always fire in the direction the shooter was placed with
+    Fixed,
+    // Turn towards the nearest ship within `detection_radius`
+    Tracking,
+}
+
+#[derive(PartialEq, Clone, RustcEncodable, RustcDecodable)]
 pub struct ShooterSpec {
     pub sprite: Sprite,
-    pub trans: Transform,
     pub bullet_spec: SpecId,
     pub firing_rate: f32,
+    pub bbox: BBox,
+    pub aim: Aim,
+    pub detection_radius: f32,
+    // How fast a tracking shooter turns, in radians per second
+    pub rotation_vel: f32,
 }
 
 #[derive(PartialEq, Clone, RustcEncodable, RustcDecodable)]
@@ -152,7 +214,22 @@ pub enum Spec {
     BulletSpec(BulletSpec),
 }
 
+#[derive(PartialEq, Clone, Copy, Show)]
+pub enum SpecKind {
+    Ship,
+    Shooter,
+    Bullet,
+}
+
 impl Spec {
+    pub fn kind(&self) -> SpecKind {
+        match *self {
+            Spec::ShipSpec(_)    => SpecKind::Ship,
+            Spec::ShooterSpec(_) => SpecKind::Shooter,
+            Spec::BulletSpec(_)  => SpecKind::Bullet,
+        }
+    }
+
     pub fn is_ship(&self) -> &ShipSpec {
         match *self {
             Spec::ShipSpec(ref spec) => spec,
@@ -180,7 +257,7 @@ pub struct GameSpec {
     pub map: Map,
     pub camera_spec: CameraSpec,
     pub ship_spec: SpecId,
-    pub shooter_spec: SpecId,
+    pub spawn_points: Vec<Vec2>,
     pub specs: Vec<Spec>,
 }
 
@@ -188,4 +265,292 @@ impl GameSpec {
     pub fn get_spec(&self, spec_id: SpecId) -> &Spec {
         &self.specs[spec_id as usize]
     }
+
+    // The spawn point furthest away from `pos`, so that ships don't
+    // respawn where they've just been shot down.
+    pub fn furthest_spawn_point(&self, pos: Vec2) -> Vec2 {
+        let mut furthest = self.spawn_points[0];
+        for p in self.spawn_points.iter() {
+            if (*p - pos).mag() > (furthest - pos).mag() {
+                furthest = *p;
+            }
+        };
+        furthest
+    }
+}
+
+// ---------------------------------------------------------------------
+// Validation
+//
+// The rest of the game assumes that specs are well formed -- e.g.
+// `Spec::is_ship` & co. panic on the wrong kind of spec -- so specs
+// coming from outside should be checked with `GameSpec::validate`
+// first.
+
+// Each error carries the path of the offending field, e.g.
+// `specs[1].bullet_spec`.
+#[derive(PartialEq, Clone, Show)]
+pub enum SpecError {
+    // The field refers to a spec that doesn't exist
+    MissingSpec(String, SpecId),
+    // The field refers to a spec of the wrong kind, the `SpecKind` is
+    // the expected one
+    WrongKind(String, SpecId, SpecKind),
+    UnknownTexture(String, TextureId),
+    // The field has a nonsensical value, the `&str` says why
+    BadValue(String, &'static str),
+}
+
+struct Validator<'a> {
+    spec: &'a GameSpec,
+    textures: &'a [TextureId],
+    errors: Vec<SpecError>,
+}
+
+impl<'a> Validator<'a> {
+    fn spec_ref(&mut self, field: String, spec_id: SpecId, kind: SpecKind) {
+        match self.spec.specs.get(spec_id as usize) {
+            None =>
+                self.errors.push(SpecError::MissingSpec(field, spec_id)),
+            Some(spec) =>
+                if spec.kind() != kind {
+                    self.errors.push(SpecError::WrongKind(field, spec_id, kind))
+                },
+        }
+    }
+
+    fn texture(&mut self, field: String, texture: TextureId) {
+        if !self.textures.contains(&texture) {
+            self.errors.push(SpecError::UnknownTexture(field, texture));
+        }
+    }
+
+    fn sprite(&mut self, field: String, sprite: &Sprite) {
+        self.texture(format!("{}.texture", field), sprite.texture);
+    }
+
+    fn check(&mut self, field: String, ok: bool, why: &'static str) {
+        if !ok {
+            self.errors.push(SpecError::BadValue(field, why));
+        }
+    }
+
+    fn bbox(&mut self, field: String, bbox: &BBox) {
+        self.check(format!("{}.rects", field), !bbox.rects.is_empty(), "should not be empty");
+    }
+
+    fn ship_spec(&mut self, field: String, spec: &ShipSpec) {
+        self.sprite(format!("{}.sprite", field), &spec.sprite);
+        self.sprite(format!("{}.sprite_accel", field), &spec.sprite_accel);
+        self.spec_ref(format!("{}.bullet_spec", field), spec.bullet_spec, SpecKind::Bullet);
+        self.bbox(format!("{}.bbox", field), &spec.bbox);
+        self.check(format!("{}.firing_interval", field), spec.firing_interval > 0., "should be positive");
+        self.check(format!("{}.health", field), spec.health > 0., "should be positive");
+        self.check(format!("{}.respawn_delay", field), spec.respawn_delay > 0., "should be positive");
+        self.check(format!("{}.collision_damage", field), spec.collision_damage >= 0., "should not be negative");
+        self.check(format!("{}.restitution", field), spec.restitution >= 0. && spec.restitution <= 1., "should be between 0 and 1");
+    }
+
+    fn shooter_spec(&mut self, field: String, spec: &ShooterSpec) {
+        self.sprite(format!("{}.sprite", field), &spec.sprite);
+        self.spec_ref(format!("{}.bullet_spec", field), spec.bullet_spec, SpecKind::Bullet);
+        self.bbox(format!("{}.bbox", field), &spec.bbox);
+        self.check(format!("{}.firing_rate", field), spec.firing_rate > 0., "should be positive");
+        self.check(format!("{}.detection_radius", field), spec.detection_radius >= 0., "should not be negative");
+        self.check(format!("{}.rotation_vel", field), spec.rotation_vel >= 0., "should not be negative");
+    }
+
+    fn bullet_spec(&mut self, field: String, spec: &BulletSpec) {
+        self.sprite(format!("{}.sprite", field), &spec.sprite);
+        self.bbox(format!("{}.bbox", field), &spec.bbox);
+        self.check(format!("{}.lifetime", field), spec.lifetime > 0., "should be positive");
+        self.check(format!("{}.damage", field), spec.damage >= 0., "should not be negative");
+    }
+
+    fn game_spec(&mut self) {
+        let spec = self.spec;
+        self.check("map.w".to_string(), spec.map.w > 0., "should be positive");
+        self.check("map.h".to_string(), spec.map.h > 0., "should be positive");
+        self.texture("map.background_texture".to_string(), spec.map.background_texture);
+        self.spec_ref("ship_spec".to_string(), spec.ship_spec, SpecKind::Ship);
+        for (i, turret) in spec.map.turrets.iter().enumerate() {
+            self.spec_ref(format!("map.turrets[{}].spec", i), turret.spec, SpecKind::Shooter);
+        };
+        self.check("spawn_points".to_string(), !spec.spawn_points.is_empty(), "should not be empty");
+        for (i, p) in spec.spawn_points.iter().enumerate() {
+            let inside = p.x >= 0. && p.x <= spec.map.w && p.y >= 0. && p.y <= spec.map.h;
+            self.check(format!("spawn_points[{}]", i), inside, "should be inside the map");
+        };
+        for (i, s) in spec.specs.iter().enumerate() {
+            let field = format!("specs[{}]", i);
+            match *s {
+                Spec::ShipSpec(ref s)    => self.ship_spec(field, s),
+                Spec::ShooterSpec(ref s) => self.shooter_spec(field, s),
+                Spec::BulletSpec(ref s)  => self.bullet_spec(field, s),
+            }
+        };
+    }
+}
+
+impl GameSpec {
+    // `textures` are the textures the sprites can use.
+    pub fn validate(&self, textures: &[TextureId]) -> Result<(), Vec<SpecError>> {
+        let mut validator = Validator{spec: self, textures: textures, errors: Vec::new()};
+        validator.game_spec();
+        if validator.errors.is_empty() { Ok(()) } else { Err(validator.errors) }
+    }
+}
+
+// ---------------------------------------------------------------------
+// Loading from JSON
+//
+// The file mirrors `GameSpec`, except that:
+//
+// * `specs` is an object from names to specs, each written as
+//   `{"ShipSpec": {...}}`, `{"BulletSpec": {...}}`, etc.  Specs get
+//   their `SpecId` from their position in the alphabetical order of the
+//   names.
+// * The fields referring to specs (`ship_spec`, `bullet_spec`, and
+//   `spec` in the map placements) contain names rather than ids.
+// * The fields referring to textures (`texture`, `background_texture`)
+//   contain texture names, resolved with the table passed to
+//   `GameSpec::from_json`.
+
+#[derive(Show)]
+pub enum LoadError {
+    Io(IoError),
+    Parse(json::ParserError),
+    Decode(json::DecoderError),
+    Malformed(String),
+    UnknownSpec(String),
+    UnknownTexture(String),
+}
+
+// `{"ShipSpec": {...}}` to what `json::Decoder` expects for
+// `Spec::ShipSpec(...)`.
+fn spec_variant(name: &String, spec: Json) -> Result<Json, LoadError> {
+    match spec {
+        Json::Object(spec) if spec.len() == 1 => {
+            let (kind, fields) = spec.into_iter().next().unwrap();
+            let mut variant = BTreeMap::new();
+            let _ = variant.insert("variant".to_string(), Json::String(kind));
+            let _ = variant.insert("fields".to_string(), Json::Array(vec![fields]));
+            Ok(Json::Object(variant))
+        },
+        _ =>
+            Err(LoadError::Malformed(format!("spec `{}` should be an object with its kind as the only key", name))),
+    }
+}
+
+fn resolve_names(json: &mut Json, specs: &HashMap<String, SpecId>, textures: &HashMap<String, TextureId>) -> Result<(), LoadError> {
+    match *json {
+        Json::Object(ref mut fields) => {
+            for (key, value) in fields.iter_mut() {
+                let id = match (key.as_slice(), &*value) {
+                    ("ship_spec", &Json::String(ref name)) |
+                    ("spec", &Json::String(ref name)) |
+                    ("bullet_spec", &Json::String(ref name)) =>
+                        match specs.get(name) {
+                            None     => return Err(LoadError::UnknownSpec(name.clone())),
+                            Some(id) => Some(*id),
+                        },
+                    ("texture", &Json::String(ref name)) |
+                    ("background_texture", &Json::String(ref name)) =>
+                        match textures.get(name) {
+                            None     => return Err(LoadError::UnknownTexture(name.clone())),
+                            Some(id) => Some(*id),
+                        },
+                    _ =>
+                        None,
+                };
+                match id {
+                    None     => try!(resolve_names(value, specs, textures)),
+                    Some(id) => *value = Json::U64(id as u64),
+                }
+            };
+            Ok(())
+        },
+        Json::Array(ref mut values) => {
+            for value in values.iter_mut() {
+                try!(resolve_names(value, specs, textures));
+            };
+            Ok(())
+        },
+        _ =>
+            Ok(()),
+    }
+}
+
+impl GameSpec {
+    pub fn from_json(s: &str, textures: &HashMap<String, TextureId>) -> Result<GameSpec, LoadError> {
+        let mut json = try!(json::from_str(s).map_err(LoadError::Parse));
+        let spec_ids = {
+            let fields = match json {
+                Json::Object(ref mut fields) => fields,
+                _ => return Err(LoadError::Malformed("the spec should be an object".to_string())),
+            };
+            let named_specs = match fields.remove("specs") {
+                Some(Json::Object(named_specs)) => named_specs,
+                _ => return Err(LoadError::Malformed("`specs` should be an object".to_string())),
+            };
+            let mut spec_ids = HashMap::new();
+            let mut specs = Vec::with_capacity(named_specs.len());
+            for (name, spec) in named_specs.into_iter() {
+                let _ = spec_ids.insert(name.clone(), specs.len() as SpecId);
+                specs.push(try!(spec_variant(&name, spec)));
+            };
+            let _ = fields.insert("specs".to_string(), Json::Array(specs));
+            spec_ids
+        };
+        try!(resolve_names(&mut json, &spec_ids, textures));
+        let mut decoder = json::Decoder::new(json);
+        Decodable::decode(&mut decoder).map_err(LoadError::Decode)
+    }
+
+    pub fn from_json_file(path: &Path, textures: &HashMap<String, TextureId>) -> Result<GameSpec, LoadError> {
+        let contents = try!(File::open(path).read_to_string().map_err(LoadError::Io));
+        GameSpec::from_json(contents.as_slice(), textures)
+    }
+}
+
+// ---------------------------------------------------------------------
+// Tests
+
+#[test]
+fn test_validate() {
+    let bad = r#"{
+        "map": {
+            "w": 0, "h": 600, "background_color": [0, 0, 0], "background_texture": "bg",
+            "turrets": [{"spec": "plane", "trans": {"pos": {"x": 0, "y": 0}, "rotation": 0}}]
+        },
+        "camera_spec": {"accel": 1, "h_pad": 10, "v_pad": 10},
+        "ship_spec": "plane",
+        "spawn_points": [{"x": 0, "y": 10}],
+        "specs": {
+            "bullet": {"BulletSpec": {
+                "sprite": {"texture": 7, "rect": {"pos": {"x": 0, "y": 0}, "w": 1, "h": 1}, "center": {"x": 0, "y": 0}, "angle": 0},
+                "vel": 10, "gravity": 0, "lifetime": 0, "damage": 1,
+                "bbox": {"rects": [{"pos": {"x": 0, "y": 0}, "w": 1, "h": 1}]}
+            }},
+            "plane": {"ShipSpec": {
+                "rotation_vel": 1, "rotation_vel_accel": 1, "accel": 1, "friction": 1, "gravity": 1,
+                "sprite": {"texture": "bg", "rect": {"pos": {"x": 0, "y": 0}, "w": 1, "h": 1}, "center": {"x": 0, "y": 0}, "angle": 0},
+                "sprite_accel": {"texture": "bg", "rect": {"pos": {"x": 0, "y": 0}, "w": 1, "h": 1}, "center": {"x": 0, "y": 0}, "angle": 0},
+                "bullet_spec": "plane", "firing_interval": 1, "shoot_from": {"x": 0, "y": 0},
+                "bbox": {"rects": [{"pos": {"x": 0, "y": 0}, "w": 1, "h": 1}]},
+                "health": 1, "respawn_delay": 0, "collision_damage": 1, "restitution": 0.5
+            }}
+        }
+    }"#;
+    let mut textures = HashMap::new();
+    let _ = textures.insert("bg".to_string(), 0);
+    let spec = GameSpec::from_json(bad, &textures).ok().unwrap();
+    let errors = spec.validate(&[0]).err().unwrap();
+    assert!(errors.contains(&SpecError::BadValue("map.w".to_string(), "should be positive")));
+    assert!(errors.contains(&SpecError::WrongKind("map.turrets[0].spec".to_string(), 1, SpecKind::Shooter)));
+    assert!(errors.contains(&SpecError::UnknownTexture("specs[0].sprite.texture".to_string(), 7)));
+    assert!(errors.contains(&SpecError::BadValue("specs[0].lifetime".to_string(), "should be positive")));
+    assert!(errors.contains(&SpecError::WrongKind("specs[1].bullet_spec".to_string(), 1, SpecKind::Bullet)));
+    assert!(errors.contains(&SpecError::BadValue("specs[1].respawn_delay".to_string(), "should be positive")));
+    assert!(errors.len() == 6);
 }